@@ -0,0 +1,343 @@
+use std::cell::RefCell;
+use std::io::{ self, BufRead, Write };
+use std::rc::Rc;
+
+use crate::cache::Cache;
+use crate::interpreter::{ integer, Arity, Callable, Interpreter, RuntimeError, Value };
+use crate::span::Span;
+
+/// A function that comes with the language rather than being declared in a
+/// script. Most of their names are keywords; the rest can be shadowed.
+#[derive(Debug, PartialEq, Clone, Copy, Hash)]
+pub enum Builtin {
+    Map,
+    Filter,
+    Reduce,
+    Fold,
+    Zip,
+    Partial,
+    Compose,
+    Pipe,
+    Memoize,
+    Forget,
+    Input,
+    Number,
+}
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        match name {
+            "map" => Some(Builtin::Map),
+            "filter" => Some(Builtin::Filter),
+            "reduce" => Some(Builtin::Reduce),
+            "fold" => Some(Builtin::Fold),
+            "zip" => Some(Builtin::Zip),
+            "partial" => Some(Builtin::Partial),
+            "compose" => Some(Builtin::Compose),
+            "pipe" => Some(Builtin::Pipe),
+            "memoize" => Some(Builtin::Memoize),
+            "forget" => Some(Builtin::Forget),
+            "input" => Some(Builtin::Input),
+            "number" => Some(Builtin::Number),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Map => "map",
+            Builtin::Filter => "filter",
+            Builtin::Reduce => "reduce",
+            Builtin::Fold => "fold",
+            Builtin::Zip => "zip",
+            Builtin::Partial => "partial",
+            Builtin::Compose => "compose",
+            Builtin::Pipe => "pipe",
+            Builtin::Memoize => "memoize",
+            Builtin::Forget => "forget",
+            Builtin::Input => "input",
+            Builtin::Number => "number",
+        }
+    }
+
+    pub fn arity(self) -> Arity {
+        match self {
+            Builtin::Map | Builtin::Filter | Builtin::Reduce | Builtin::Zip => Arity::Exact(2),
+            Builtin::Fold => Arity::Exact(3),
+            Builtin::Partial | Builtin::Compose | Builtin::Pipe => Arity::AtLeast(1),
+            Builtin::Memoize => Arity::Between(1, 2),
+            Builtin::Forget | Builtin::Number => Arity::Exact(1),
+            Builtin::Input => Arity::Between(0, 1),
+        }
+    }
+}
+
+impl Interpreter {
+    /// Runs a built-in. Collections always come first, so the built-ins that
+    /// take one read naturally at the end of a `|>` pipeline.
+    pub(crate) fn call_builtin(&mut self, builtin: Builtin, args: Vec<Value>, span: Span) -> Result<Value, RuntimeError> {
+        if !builtin.arity().accepts(args.len()) {
+            return Err(RuntimeError::ArityMismatch {
+                name: builtin.name().to_string(),
+                expected: builtin.arity(),
+                found: args.len(),
+                span,
+            });
+        }
+
+        match builtin {
+            // Maps keep their keys and have their values transformed; everything else becomes a list.
+            Builtin::Map => {
+                let function = callback(&args[1], 1, span)?;
+                if let Value::Map(entries) = &args[0] {
+                    let mut mapped = Vec::with_capacity(entries.len());
+                    for (key, value) in entries.iter() {
+                        mapped.push((key.clone(), self.call(&function, vec![value.clone()], span)?));
+                    }
+                    return Ok(Value::Map(Rc::new(mapped)));
+                }
+                let mut mapped = Vec::new();
+                for (_, item) in self.iterate(&args[0], span)? {
+                    mapped.push(self.call(&function, vec![item], span)?);
+                }
+                Ok(Value::List(Rc::new(mapped)))
+            }
+            // Strings and maps stay strings and maps; ranges become lists.
+            Builtin::Filter => {
+                let predicate = callback(&args[1], 1, span)?;
+                match &args[0] {
+                    Value::String(s) => {
+                        let mut kept = String::new();
+                        for c in s.chars() {
+                            if self.test(&predicate, Value::String(c.to_string()), span)? {
+                                kept.push(c);
+                            }
+                        }
+                        Ok(Value::String(kept))
+                    }
+                    Value::Map(entries) => {
+                        let mut kept = Vec::new();
+                        for (key, value) in entries.iter() {
+                            if self.test(&predicate, value.clone(), span)? {
+                                kept.push((key.clone(), value.clone()));
+                            }
+                        }
+                        Ok(Value::Map(Rc::new(kept)))
+                    }
+                    collection => {
+                        let mut kept = Vec::new();
+                        for (_, item) in self.iterate(collection, span)? {
+                            if self.test(&predicate, item.clone(), span)? {
+                                kept.push(item);
+                            }
+                        }
+                        Ok(Value::List(Rc::new(kept)))
+                    }
+                }
+            }
+            Builtin::Reduce => {
+                let function = callback(&args[1], 2, span)?;
+                let mut items = self.iterate(&args[0], span)?.map(|(_, item)| item);
+                let Some(mut accumulator) = items.next() else {
+                    return Err(RuntimeError::EmptyReduce { found: args[0].type_name(), span });
+                };
+                for item in items {
+                    accumulator = self.call(&function, vec![accumulator, item], span)?;
+                }
+                Ok(accumulator)
+            }
+            Builtin::Fold => {
+                let function = callback(&args[2], 2, span)?;
+                let mut accumulator = args[1].clone();
+                for (_, item) in self.iterate(&args[0], span)? {
+                    accumulator = self.call(&function, vec![accumulator, item], span)?;
+                }
+                Ok(accumulator)
+            }
+            // Pairs items up until the shorter side runs out.
+            Builtin::Zip => {
+                let right = self.iterate(&args[1], span)?;
+                let pairs = self
+                    .iterate(&args[0], span)?
+                    .zip(right)
+                    .map(|((_, l), (_, r))| Value::List(Rc::new(vec![l, r])))
+                    .collect();
+                Ok(Value::List(Rc::new(pairs)))
+            }
+            // Partials of partials are flattened, so their names stay readable.
+            Builtin::Partial => {
+                let mut bound = args;
+                let mut function = expect_function(&bound.remove(0), span)?;
+                if let Callable::Partial { function: inner, bound: inner_bound } = &*function {
+                    bound = inner_bound.iter().cloned().chain(bound).collect();
+                    function = Rc::clone(inner);
+                }
+                if let Arity::Exact(n) | Arity::Between(_, n) = function.arity() {
+                    if bound.len() > n {
+                        return Err(RuntimeError::ArityMismatch {
+                            name: function.name(),
+                            expected: function.arity(),
+                            found: bound.len(),
+                            span,
+                        });
+                    }
+                }
+                Ok(Value::Function(Rc::new(Callable::Partial { function, bound })))
+            }
+            Builtin::Compose => pipeline(args.iter().rev(), span),
+            Builtin::Pipe => pipeline(args.iter(), span),
+            Builtin::Memoize => {
+                let function = expect_function(&args[0], span)?;
+                let capacity = match args.get(1) {
+                    Some(capacity) => cache_capacity(capacity, span)?,
+                    None => None,
+                };
+                Ok(memoize(function, capacity))
+            }
+            Builtin::Forget => {
+                let function = expect_function(&args[0], span)?;
+                match &*function {
+                    Callable::Memoized { cache, .. } => {
+                        cache.borrow_mut().clear();
+                        Ok(Value::Null)
+                    }
+                    other => Err(RuntimeError::NotMemoized { name: other.name(), span }),
+                }
+            }
+            // Shows the prompt, if any, and reads one line without its line
+            // ending. Once the input runs out there is nothing left but `null`.
+            Builtin::Input => {
+                if let Some(prompt) = args.first() {
+                    print!("{}", prompt);
+                    let _ = io::stdout().flush();
+                }
+                let mut line = String::new();
+                match io::stdin().lock().read_line(&mut line) {
+                    Ok(0) | Err(_) => Ok(Value::Null),
+                    Ok(_) => {
+                        let trimmed = line.trim_end_matches(['\n', '\r']).len();
+                        line.truncate(trimmed);
+                        Ok(Value::String(line))
+                    }
+                }
+            }
+            Builtin::Number => match &args[0] {
+                number @ (Value::Int(_) | Value::Number(_)) => Ok(number.clone()),
+                Value::String(text) => parse_number(text.trim()).ok_or_else(|| RuntimeError::InvalidNumber {
+                    text: text.clone(),
+                    span,
+                }),
+                other => Err(RuntimeError::TypeMismatch { expected: "string", found: other.type_name(), span }),
+            },
+        }
+    }
+
+    /// Calls a predicate, which must answer with a bool.
+    fn test(&mut self, predicate: &Callable, item: Value, span: Span) -> Result<bool, RuntimeError> {
+        match self.call(predicate, vec![item], span)? {
+            Value::Bool(b) => Ok(b),
+            other => Err(RuntimeError::TypeMismatch { expected: "bool", found: other.type_name(), span }),
+        }
+    }
+}
+
+/// Wraps `function` in a fresh cache.
+pub(crate) fn memoize(function: Rc<Callable>, capacity: Option<usize>) -> Value {
+    Value::Function(Rc::new(Callable::Memoized { function, cache: RefCell::new(Cache::new(capacity)) }))
+}
+
+/// Checks a cache capacity, where `null` means unlimited.
+pub(crate) fn cache_capacity(value: &Value, span: Span) -> Result<Option<usize>, RuntimeError> {
+    if let Value::Null = value {
+        return Ok(None);
+    }
+    match integer(value, span)? {
+        capacity if capacity >= 1 => Ok(Some(capacity as usize)),
+        capacity => Err(RuntimeError::InvalidCapacity { capacity, span }),
+    }
+}
+
+/// Reads `text` as an integer if it is one, or else as a finite float.
+fn parse_number(text: &str) -> Option<Value> {
+    if let Ok(n) = text.parse::<i64>() {
+        return Some(Value::Int(n));
+    }
+    text.parse::<f64>().ok().filter(|n| n.is_finite()).map(Value::Number)
+}
+
+/// Chains `functions` so each one is called with the result of the one
+/// before it. Every function after the first gets a single argument.
+fn pipeline<'a>(mut functions: impl Iterator<Item = &'a Value>, span: Span) -> Result<Value, RuntimeError> {
+    let first = functions.next().expect("`pipe` and `compose` take at least one function");
+    let mut chained = expect_function(first, span)?;
+    for next in functions {
+        chained = Rc::new(Callable::Composed { first: chained, second: callback(next, 1, span)? });
+    }
+    Ok(Value::Function(chained))
+}
+
+fn expect_function(value: &Value, span: Span) -> Result<Rc<Callable>, RuntimeError> {
+    match value {
+        Value::Function(callable) => Ok(Rc::clone(callable)),
+        other => Err(RuntimeError::TypeMismatch { expected: "function", found: other.type_name(), span }),
+    }
+}
+
+/// Checks that a function handed to a built-in will accept the `arity`
+/// arguments it is going to be called with, before any of the work starts.
+fn callback(value: &Value, arity: usize, span: Span) -> Result<Rc<Callable>, RuntimeError> {
+    let callable = expect_function(value, span)?;
+    if !callable.arity().accepts(arity) {
+        return Err(RuntimeError::ArityMismatch {
+            name: callable.name(),
+            expected: callable.arity(),
+            found: arity,
+            span,
+        });
+    }
+    Ok(callable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::Parser;
+
+    fn run(source: &str) -> Result<Value, RuntimeError> {
+        let tokens = tokenize(source).expect("source should lex");
+        let ast = Parser::new(tokens).parse().expect("source should parse");
+        Interpreter::new().interpret(&ast)
+    }
+
+    #[test]
+    fn filter_keeps_maps_and_strings_as_they_are() {
+        let map = run("filter(#{ a: 1, b: 2, c: 3 }, (n) -> n != 2)").unwrap();
+        assert_eq!(map.to_string(), r#"#{"a": 1, "c": 3}"#);
+        assert_eq!(run("filter(\"cranberry\", (c) -> c != \"r\")"), Ok(Value::String("canbey".to_string())));
+        assert_eq!(run("filter(1..5, (n) -> n % 2 == 0)").unwrap().to_string(), "[2, 4]");
+    }
+
+    #[test]
+    fn reduce_needs_something_to_start_from() {
+        assert!(matches!(run("reduce([], (a, b) -> a + b)"), Err(RuntimeError::EmptyReduce { found: "list", .. })));
+        assert_eq!(run("reduce([5], (a, b) -> a + b)"), Ok(Value::Int(5)));
+        assert_eq!(run("fold([], 0, (a, b) -> a + b)"), Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn callbacks_are_checked_before_any_work_starts() {
+        let err = run("map([1, 2], (a, b) -> a)").unwrap_err();
+        assert!(matches!(err, RuntimeError::ArityMismatch { expected: Arity::Exact(2), found: 1, .. }));
+        let err = run("reduce([1, 2], (a) -> a)").unwrap_err();
+        assert!(matches!(err, RuntimeError::ArityMismatch { expected: Arity::Exact(1), found: 2, .. }));
+        assert!(matches!(run("map([1], 3)"), Err(RuntimeError::TypeMismatch { expected: "function", .. })));
+    }
+
+    #[test]
+    fn builtins_slot_into_a_pipeline() {
+        let source = "1..=10 |> filter((n) -> n % 2 == 0) |> map((n) -> n * n) |> reduce((a, b) -> a + b)";
+        assert_eq!(run(source), Ok(Value::Int(220)));
+        assert_eq!(run("[1, 2] |> zip(\"ab\")").unwrap().to_string(), r#"[[1, "a"], [2, "b"]]"#);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::{ BTreeMap, HashMap };
+
+use crate::interpreter::Value;
+
+/// The arguments of one call, compared the way `==` compares values.
+#[derive(Debug, PartialEq, Hash, Clone)]
+struct Key(Vec<Value>);
+
+// Keys holding `NaN` are never equal to themselves, so `Cache` never stores one.
+impl Eq for Key {}
+
+/// Results of a memoized function, by the arguments they were computed
+/// from. With a capacity, the least recently used result is dropped to make
+/// room for a new one.
+#[derive(Debug)]
+pub struct Cache {
+    capacity: Option<usize>,
+    /// Each result, and when it was last used.
+    results: HashMap<Key, (Value, u64)>,
+    /// The keys by when they were last used, oldest first.
+    recency: BTreeMap<u64, Key>,
+    clock: u64,
+}
+
+impl Cache {
+    pub fn new(capacity: Option<usize>) -> Self {
+        Cache { capacity, results: HashMap::new(), recency: BTreeMap::new(), clock: 0 }
+    }
+
+    /// The result cached for `args`, marking it as just used.
+    pub fn get(&mut self, args: &[Value]) -> Option<Value> {
+        if !cacheable(args) {
+            return None;
+        }
+        let key = Key(args.to_vec());
+        let (value, last_used) = self.results.get_mut(&key)?;
+        self.recency.remove(last_used);
+        self.clock += 1;
+        *last_used = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(value.clone())
+    }
+
+    /// Stores a result. Replacing one that is already cached never pushes
+    /// anything else out.
+    pub fn insert(&mut self, args: Vec<Value>, value: Value) {
+        if !cacheable(&args) {
+            return;
+        }
+        let key = Key(args);
+        if let Some((_, last_used)) = self.results.get(&key) {
+            self.recency.remove(last_used);
+        } else if self.capacity.is_some_and(|capacity| self.results.len() >= capacity) {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.results.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.results.insert(key, (value, self.clock));
+    }
+
+    pub fn clear(&mut self) {
+        self.results.clear();
+        self.recency.clear();
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+}
+
+/// Arguments containing `NaN` never equal themselves, so a result stored for
+/// them could neither be found again nor be removed by eviction.
+#[allow(clippy::eq_op)]
+fn cacheable(args: &[Value]) -> bool {
+    args == args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(n: i64) -> Vec<Value> {
+        vec![Value::Int(n)]
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_result() {
+        let mut cache = Cache::new(Some(2));
+        cache.insert(args(1), Value::Int(10));
+        cache.insert(args(2), Value::Int(20));
+        assert_eq!(cache.get(&args(1)), Some(Value::Int(10)));
+        cache.insert(args(3), Value::Int(30));
+        assert_eq!(cache.get(&args(2)), None);
+        assert_eq!(cache.get(&args(1)), Some(Value::Int(10)));
+        assert_eq!(cache.get(&args(3)), Some(Value::Int(30)));
+    }
+
+    #[test]
+    fn replacing_a_result_keeps_the_others() {
+        let mut cache = Cache::new(Some(2));
+        cache.insert(args(1), Value::Int(10));
+        cache.insert(args(2), Value::Int(20));
+        cache.insert(args(2), Value::Int(21));
+        assert_eq!(cache.get(&args(1)), Some(Value::Int(10)));
+        assert_eq!(cache.get(&args(2)), Some(Value::Int(21)));
+        assert_eq!(cache.results.len(), cache.recency.len());
+    }
+
+    #[test]
+    fn arguments_match_by_equality() {
+        let mut cache = Cache::new(None);
+        cache.insert(vec![Value::Number(1.0)], Value::Bool(true));
+        assert_eq!(cache.get(&args(1)), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut cache = Cache::new(None);
+        cache.insert(args(1), Value::Int(10));
+        cache.clear();
+        assert_eq!(cache.get(&args(1)), None);
+        assert!(cache.recency.is_empty());
+    }
+
+    #[test]
+    fn results_for_nan_arguments_are_not_kept() {
+        let mut cache = Cache::new(Some(1));
+        cache.insert(args(1), Value::Int(10));
+        let nan = vec![Value::List(std::rc::Rc::new(vec![Value::Number(f64::NAN)]))];
+        cache.insert(nan.clone(), Value::Int(0));
+        assert_eq!(cache.get(&nan), None);
+        assert_eq!(cache.results.len(), 1);
+        assert_eq!(cache.get(&args(1)), Some(Value::Int(10)));
+        assert_eq!(cache.recency.len(), 1);
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt::Write;
+
+use crate::interpreter::RuntimeError;
+use crate::lexer::LexError;
+use crate::parser::{ ParseError, ParseWarning };
+use crate::span::Span;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// A message about a region of source code, ready to be rendered.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic { severity: Severity::Error, message: message.into(), span, hint: None }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic { severity: Severity::Warning, message: message.into(), span, hint: None }
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+impl From<&LexError> for Diagnostic {
+    fn from(err: &LexError) -> Self {
+        Diagnostic::error(err.to_string(), err.span())
+    }
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(err: &ParseError) -> Self {
+        let diagnostic = Diagnostic::error(err.to_string(), err.span());
+        match err {
+            ParseError::InvalidAssignmentTarget { .. } => {
+                diagnostic.with_hint("only variables, list elements and map fields can be assigned to")
+            }
+            _ => diagnostic,
+        }
+    }
+}
+
+impl From<&ParseWarning> for Diagnostic {
+    fn from(warning: &ParseWarning) -> Self {
+        let diagnostic = Diagnostic::warning(warning.to_string(), warning.span());
+        match warning {
+            ParseWarning::NonExhaustiveMatch { .. } => {
+                diagnostic.with_hint("add a `default => ...` arm for values no other arm matches")
+            }
+        }
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(err: &RuntimeError) -> Self {
+        let diagnostic = Diagnostic::error(err.to_string(), err.span());
+        match err {
+            RuntimeError::UndefinedVariable { suggestion: Some(name), .. }
+            | RuntimeError::UndeclaredAssignment { suggestion: Some(name), .. }
+            | RuntimeError::KeyNotFound { suggestion: Some(name), .. } => {
+                diagnostic.with_hint(format!("did you mean `{}`?", name))
+            }
+            RuntimeError::UndeclaredAssignment { name, suggestion: None, .. } => {
+                diagnostic.with_hint(format!("declare it first with `mutate {} = ...`", name))
+            }
+            RuntimeError::AssignToConstant { name, .. } => {
+                diagnostic.with_hint(format!("did you mean `mutate`? `{}` was declared with `const`", name))
+            }
+            RuntimeError::InvalidShift { .. } => diagnostic.with_hint("shift amounts go from 0 to 63"),
+            RuntimeError::IntegerOutOfRange { .. } => {
+                diagnostic.with_hint(format!("integers go from {} to {}", i64::MIN, i64::MAX))
+            }
+            RuntimeError::EmptyReduce { .. } => {
+                diagnostic.with_hint("use `fold` to start from a value of your own instead")
+            }
+            RuntimeError::InvalidCapacity { .. } => diagnostic.with_hint("use `null` for a cache without a limit"),
+            RuntimeError::NotMemoized { name, .. } => {
+                diagnostic.with_hint(format!("only functions made with `memoize` have a cache; try `memoize({})`", name))
+            }
+            RuntimeError::IndexOutOfBounds { len: 0, .. } => diagnostic.with_hint("the list is empty"),
+            RuntimeError::IndexOutOfBounds { len, .. } => {
+                diagnostic.with_hint(format!("valid indexes are 0 to {}, or -{} to -1 from the end", len - 1, len))
+            }
+            _ => diagnostic,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RenderMode {
+    /// Human-readable output with ANSI colors.
+    Color,
+    /// Human-readable output without escape codes.
+    Plain,
+    /// One JSON object per diagnostic, for editors and other tools.
+    Json,
+}
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders diagnostics against the source they refer to.
+pub struct Renderer<'a> {
+    source_name: &'a str,
+    source: &'a str,
+    mode: RenderMode,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(source_name: &'a str, source: &'a str, mode: RenderMode) -> Self {
+        Renderer { source_name, source, mode }
+    }
+
+    /// Renders one diagnostic, ending with a newline. JSON mode always
+    /// produces exactly one line.
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        match self.mode {
+            RenderMode::Json => self.render_json(diagnostic),
+            RenderMode::Color => self.render_human(diagnostic, true),
+            RenderMode::Plain => self.render_human(diagnostic, false),
+        }
+    }
+
+    fn render_human(&self, diagnostic: &Diagnostic, color: bool) -> String {
+        let paint = |style: &'static str| if color { style } else { "" };
+        let reset = paint(RESET);
+        let severity_color = match diagnostic.severity {
+            Severity::Error => paint(RED),
+            Severity::Warning => paint(YELLOW),
+        };
+        let gutter = paint(BLUE);
+
+        let span = diagnostic.span;
+        let line_text = self.source.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+        let line_number = span.line.to_string();
+        let padding = " ".repeat(line_number.len());
+
+        // Underline up to the end of the first line of the span, and at least one column.
+        let available = line_text.chars().count().saturating_sub(span.column.saturating_sub(1));
+        let width = self.source.get(span.start..span.end)
+            .map(|text| text.chars().take_while(|c| *c != '\n').count())
+            .unwrap_or(0)
+            .min(available)
+            .max(1);
+        let indent: String = line_text.chars()
+            .take(span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{}{}{}: {}{}{}", severity_color, diagnostic.severity.label(), reset, paint(BOLD), diagnostic.message, reset);
+        let _ = writeln!(out, "{}{}-->{} {}:{}:{}", padding, gutter, reset, self.source_name, span.line, span.column);
+        let _ = writeln!(out, "{} {}|{}", padding, gutter, reset);
+        let _ = writeln!(out, "{}{} |{} {}", gutter, line_number, reset, line_text);
+        let _ = writeln!(out, "{} {}|{} {}{}{}{}", padding, gutter, reset, indent, severity_color, "^".repeat(width), reset);
+        if let Some(hint) = &diagnostic.hint {
+            let _ = writeln!(out, "{} {}={} {}hint{}: {}", padding, gutter, reset, paint(CYAN), reset, hint);
+        }
+        out
+    }
+
+    fn render_json(&self, diagnostic: &Diagnostic) -> String {
+        let span = diagnostic.span;
+        let hint = match &diagnostic.hint {
+            Some(hint) => json_string(hint),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"severity\":\"{}\",\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"start\":{},\"end\":{},\"hint\":{}}}\n",
+            diagnostic.severity.label(),
+            json_string(&diagnostic.message),
+            json_string(self.source_name),
+            span.line,
+            span.column,
+            span.start,
+            span.end,
+            hint,
+        )
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Picks the candidate closest to `name` by edit distance, if any is close
+/// enough to plausibly be a typo.
+pub fn closest_match<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Edit distance where swapping two adjacent characters counts as a single edit,
+/// since that is the most common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_is_one_line_with_control_characters_escaped() {
+        let renderer = Renderer::new("a\"b.crb", "x", RenderMode::Json);
+        let diagnostic = Diagnostic::error("line\none\ttab\u{1}bell\\", Span::new(0, 1, 1, 1));
+        let rendered = renderer.render(&diagnostic);
+        assert_eq!(rendered.lines().count(), 1);
+        assert!(rendered.contains(r#""message":"line\none\ttab\u0001bell\\""#));
+        assert!(rendered.contains(r#""file":"a\"b.crb""#));
+        assert!(rendered.contains(r#""hint":null"#));
+    }
+
+    #[test]
+    fn underline_keeps_tabs_in_its_indent_and_stops_at_the_line_end() {
+        let source = "\tfoo bar\nbaz";
+        let renderer = Renderer::new("t.crb", source, RenderMode::Plain);
+        let underline = |span| renderer.render(&Diagnostic::error("oops", span)).lines().nth(4).unwrap().to_string();
+        assert_eq!(underline(Span::new(1, 4, 1, 2)), "  | \t^^^");
+        // A span running onto the next line is only underlined up to the end of its first.
+        assert_eq!(underline(Span::new(5, 12, 1, 6)), "  | \t    ^^^");
+        // An empty span still gets a caret.
+        assert_eq!(underline(Span::new(8, 8, 1, 9)), "  | \t       ^");
+    }
+
+    #[test]
+    fn swapped_letters_count_as_one_edit() {
+        assert_eq!(edit_distance("pritn", "print"), 1);
+        assert_eq!(edit_distance("abc", "ca"), 3);
+        assert_eq!(closest_match("lenght", ["length", "left"]), Some("length"));
+        assert_eq!(closest_match("xs", ["ys", "x"]), Some("x"));
+        assert_eq!(closest_match("total", ["count"]), None);
+    }
+}
@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::interpreter::Value;
+
+/// A shared handle to a scope. Closures hold on to the scope they were
+/// created in, so scopes outlive the blocks that introduced them.
+pub type Env = Rc<RefCell<Environment>>;
+
+/// The bindings of one scope (a block, a function call or the globals) and
+/// a link to the scope around it.
+#[derive(Default)]
+pub struct Environment {
+    /// Each binding is `(is_const, value)`.
+    values: HashMap<String, (bool, Value)>,
+    parent: Option<Env>,
+}
+
+/// Why an assignment was refused.
+pub enum AssignError {
+    Constant,
+    Undeclared,
+}
+
+impl Environment {
+    pub fn new_global() -> Env {
+        Rc::new(RefCell::new(Environment::default()))
+    }
+
+    pub fn new_child(parent: &Env) -> Env {
+        Rc::new(RefCell::new(Environment { values: HashMap::new(), parent: Some(Rc::clone(parent)) }))
+    }
+
+    /// Looks `name` up here and then in each enclosing scope.
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match self.values.get(name) {
+            Some((_, value)) => Some(value.clone()),
+            None => self.parent.as_ref()?.borrow().get(name),
+        }
+    }
+
+    /// Whether `name` is declared in this very scope, ignoring enclosing ones.
+    pub fn is_declared_here(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    /// Declares `name` in this scope, shadowing any binding further out.
+    pub fn declare(&mut self, name: &str, is_immut: bool, value: Value) {
+        self.values.insert(name.to_string(), (is_immut, value));
+    }
+
+    /// Changes the nearest binding of `name` in place through `change`, so
+    /// that part of a value can be replaced without copying the rest.
+    pub fn update<T>(&mut self, name: &str, change: impl FnOnce(&mut Value) -> T) -> Result<T, AssignError> {
+        match self.values.get_mut(name) {
+            Some((true, _)) => Err(AssignError::Constant),
+            Some((false, slot)) => Ok(change(slot)),
+            None => match &self.parent {
+                Some(parent) => parent.borrow_mut().update(name, change),
+                None => Err(AssignError::Undeclared),
+            },
+        }
+    }
+
+    /// Every name visible from this scope.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.values.keys().cloned().collect();
+        if let Some(parent) = &self.parent {
+            names.extend(parent.borrow().names());
+        }
+        names
+    }
+
+    /// The bindings of this scope alone, as `(name, is_const, value)`.
+    pub fn bindings(&self) -> Vec<(String, bool, Value)> {
+        self.values
+            .iter()
+            .map(|(name, (is_immut, value))| (name.clone(), *is_immut, value.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_child_scope_shadows_without_touching_its_parent() {
+        let global = Environment::new_global();
+        global.borrow_mut().declare("x", false, Value::Int(1));
+        let block = Environment::new_child(&global);
+        block.borrow_mut().declare("x", true, Value::Int(2));
+        assert_eq!(block.borrow().get("x"), Some(Value::Int(2)));
+        assert_eq!(global.borrow().get("x"), Some(Value::Int(1)));
+        assert!(block.borrow().is_declared_here("x"));
+        assert!(!Environment::new_child(&global).borrow().is_declared_here("x"));
+    }
+
+    #[test]
+    fn update_from_an_inner_scope_reaches_the_nearest_binding() {
+        let global = Environment::new_global();
+        global.borrow_mut().declare("total", false, Value::Int(1));
+        global.borrow_mut().declare("limit", true, Value::Int(10));
+        let inner = Environment::new_child(&Environment::new_child(&global));
+
+        assert!(inner.borrow_mut().update("total", |slot| *slot = Value::Int(5)).is_ok());
+        assert_eq!(global.borrow().get("total"), Some(Value::Int(5)));
+        assert!(matches!(inner.borrow_mut().update("limit", |slot| *slot = Value::Null), Err(AssignError::Constant)));
+        assert_eq!(global.borrow().get("limit"), Some(Value::Int(10)));
+        assert!(matches!(inner.borrow_mut().update("missing", |_| ()), Err(AssignError::Undeclared)));
+    }
+}
@@ -1,90 +1,1430 @@
-use std::collections::HashMap;
-use crate::parser::{ Expr, BinOp };
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::rc::Rc;
+use std::error::Error;
+use std::fmt;
+use std::hash::{ Hash, Hasher };
+use crate::builtins::{ cache_capacity, memoize, Builtin };
+use crate::cache::Cache;
+use crate::diagnostic::closest_match;
+use crate::environment::{ AssignError, Env, Environment };
+use crate::parser::{ Expr, ExprKind, BinOp, Pattern, UnaryOp };
+use crate::span::Span;
 
 #[derive(Debug, Clone)]
 pub enum Value {
+    /// What statements, empty blocks and `return;` produce, and what `nullify` stores.
+    Null,
+    /// Integer literals and whole-number arithmetic on them.
+    Int(i64),
+    /// Floating point, for anything with a fraction.
     Number(f64),
+    Bool(bool),
+    String(String),
+    /// A half-open range of integers, `start..end`.
+    Range(i64, i64),
+    /// Shared until written to, so lists behave like values: assigning one
+    /// to another variable and then changing either leaves the other alone.
+    List(Rc<Vec<Value>>),
+    /// String keys in insertion order. Shared until written to, like lists.
+    Map(Rc<Vec<(String, Value)>>),
+    Function(Rc<Callable>),
 }
 
+/// Anything a call can be made on.
+#[derive(Debug)]
+pub enum Callable {
+    /// Declared with `fn`, or a lambda.
+    Function(Function),
+    /// `f >> g` or `g << f`: calls `first`, then `second` with the result.
+    Composed { first: Rc<Callable>, second: Rc<Callable> },
+    /// `partial(function, bound...)`: calls `function` with `bound` in front
+    /// of whatever arguments are passed.
+    Partial { function: Rc<Callable>, bound: Vec<Value> },
+    /// `memoize(function)` or `memoize fn`: remembers what `function`
+    /// returned for each set of arguments.
+    Memoized { function: Rc<Callable>, cache: RefCell<Cache> },
+    Builtin(Builtin),
+}
+
+/// How many arguments a callable takes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Arity {
+    Exact(usize),
+    /// Variadic built-ins take any number from this up.
+    AtLeast(usize),
+    /// Built-ins with optional arguments take anything from the first to the second.
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(self, count: usize) -> bool {
+        match self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+            Arity::Between(min, max) => (min..=max).contains(&count),
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "{}+", n),
+            Arity::Between(min, max) => write!(f, "{}-{}", min, max),
+        }
+    }
+}
+
+impl Callable {
+    pub fn name(&self) -> String {
+        match self {
+            Callable::Function(function) => function.name.clone(),
+            Callable::Composed { first, second } => format!("{} >> {}", first.name(), second.name()),
+            Callable::Partial { function, bound } => {
+                let parts: Vec<String> = std::iter::once(function.name()).chain(bound.iter().map(Value::repr)).collect();
+                format!("partial({})", parts.join(", "))
+            }
+            Callable::Memoized { function, cache } => match cache.borrow().capacity() {
+                Some(capacity) => format!("memoize({}, {})", function.name(), capacity),
+                None => format!("memoize({})", function.name()),
+            },
+            Callable::Builtin(builtin) => builtin.name().to_string(),
+        }
+    }
+
+    /// How many arguments a call must pass.
+    pub fn arity(&self) -> Arity {
+        match self {
+            Callable::Function(function) => Arity::Exact(function.params.len()),
+            Callable::Composed { first, .. } | Callable::Memoized { function: first, .. } => first.arity(),
+            Callable::Partial { function, bound } => match function.arity() {
+                Arity::Exact(n) => Arity::Exact(n.saturating_sub(bound.len())),
+                Arity::AtLeast(n) => Arity::AtLeast(n.saturating_sub(bound.len())),
+                Arity::Between(min, max) => Arity::Between(min.saturating_sub(bound.len()), max.saturating_sub(bound.len())),
+            },
+            Callable::Builtin(builtin) => builtin.arity(),
+        }
+    }
+}
+
+/// A function declared with `fn`, or a lambda.
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expr,
+    /// The scope the function was declared in, captured by reference.
+    pub closure: Env,
+}
+
+impl fmt::Debug for Function {
+    // The closure can contain the function itself, so it is left out.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Function").field("name", &self.name).field("params", &self.params).finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) => true,
+            (Value::Int(l), Value::Int(r)) => l == r,
+            (Value::Number(l), Value::Number(r)) => l == r,
+            // Exact, rather than through a rounding `as f64`, so equal values hash alike.
+            (Value::Int(i), Value::Number(n)) | (Value::Number(n), Value::Int(i)) => whole(*n) == Some(*i),
+            (Value::Bool(l), Value::Bool(r)) => l == r,
+            (Value::String(l), Value::String(r)) => l == r,
+            (Value::Range(l_start, l_end), Value::Range(r_start, r_end)) => l_start == r_start && l_end == r_end,
+            (Value::List(l), Value::List(r)) => l == r,
+            // Maps with the same entries are equal whatever order they were inserted in.
+            (Value::Map(l), Value::Map(r)) => {
+                l.len() == r.len() && l.iter().all(|(key, value)| map_get(r, key) == Some(value))
+            }
+            // Functions are only equal to themselves.
+            (Value::Function(l), Value::Function(r)) => match (&**l, &**r) {
+                (Callable::Builtin(l), Callable::Builtin(r)) => l == r,
+                _ => Rc::ptr_eq(l, r),
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Hash for Value {
+    /// Agrees with `==`: a whole float hashes like the integer it equals, and
+    /// a map hashes the same whatever order its entries are in.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Null => {}
+            Value::Int(n) => n.hash(state),
+            Value::Number(n) => match whole(*n) {
+                Some(i) => i.hash(state),
+                None => n.to_bits().hash(state),
+            },
+            Value::Bool(b) => b.hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Range(start, end) => (start, end).hash(state),
+            Value::List(items) => items.hash(state),
+            Value::Map(entries) => {
+                let mut combined: u64 = 0;
+                for entry in entries.iter() {
+                    let mut hasher = DefaultHasher::new();
+                    entry.hash(&mut hasher);
+                    combined = combined.wrapping_add(hasher.finish());
+                }
+                combined.hash(state)
+            }
+            Value::Function(callable) => match &**callable {
+                Callable::Builtin(builtin) => builtin.hash(state),
+                _ => Rc::as_ptr(callable).hash(state),
+            },
+        }
+    }
+}
+
+impl Value {
+    /// The value as a float, if it is a number of either kind.
+    fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Int(_) => "integer",
+            Value::Number(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::String(_) => "string",
+            Value::Range(..) => "range",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Function(_) => "function",
+        }
+    }
+
+    /// The value as it would be written in source, e.g. strings are quoted.
+    /// `Display` shows strings bare, which is what `print` wants.
+    pub fn repr(&self) -> String {
+        match self {
+            Value::String(s) => format!("{:?}", s),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Range(start, end) => write!(f, "{}..{}", start, end),
+            Value::List(items) => {
+                let items: Vec<String> = items.iter().map(Value::repr).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Map(entries) => {
+                let entries: Vec<String> =
+                    entries.iter().map(|(key, value)| format!("{:?}: {}", key, value.repr())).collect();
+                write!(f, "#{{{}}}", entries.join(", "))
+            }
+            Value::Function(callable) => write!(f, "<fn {}/{}>", callable.name(), callable.arity()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RuntimeError {
+    /// `suggestion` is the closest name in scope, if one looks like a typo of `name`.
+    UndefinedVariable { name: String, suggestion: Option<String>, span: Span },
+    UndeclaredAssignment { name: String, suggestion: Option<String>, span: Span },
+    AssignToConstant { name: String, span: Span },
+    AlreadyDeclared { name: String, span: Span },
+    InvalidOperands { op: BinOp, left: &'static str, right: &'static str, span: Span },
+    /// A value of type `found` was used where only `expected` makes sense.
+    TypeMismatch { expected: &'static str, found: &'static str, span: Span },
+    ArityMismatch { name: String, expected: Arity, found: usize, span: Span },
+    /// More than `limit` calls were nested.
+    StackOverflow { limit: usize, span: Span },
+    /// `index` is as written, before negative indexes are counted from the end.
+    IndexOutOfBounds { index: i64, len: usize, span: Span },
+    /// `suggestion` is the closest key in the map, if one looks like a typo of `key`.
+    KeyNotFound { key: String, suggestion: Option<String>, span: Span },
+    InvalidSlice { start: i64, end: i64, len: usize, span: Span },
+    InvalidShift { amount: i64, span: Span },
+    /// A whole float too large (or small) to use where an integer is needed.
+    IntegerOutOfRange { value: f64, span: Span },
+    /// `reduce` was given a collection of type `found` with nothing in it.
+    EmptyReduce { found: &'static str, span: Span },
+    InvalidCapacity { capacity: i64, span: Span },
+    /// `forget` was given a function that isn't memoized.
+    NotMemoized { name: String, span: Span },
+    /// `number` was given a string that doesn't spell a number.
+    InvalidNumber { text: String, span: Span },
+}
+
+impl RuntimeError {
+    pub fn span(&self) -> Span {
+        match self {
+            RuntimeError::UndefinedVariable { span, .. }
+            | RuntimeError::UndeclaredAssignment { span, .. }
+            | RuntimeError::AssignToConstant { span, .. }
+            | RuntimeError::AlreadyDeclared { span, .. }
+            | RuntimeError::InvalidOperands { span, .. }
+            | RuntimeError::TypeMismatch { span, .. }
+            | RuntimeError::ArityMismatch { span, .. }
+            | RuntimeError::StackOverflow { span, .. }
+            | RuntimeError::IndexOutOfBounds { span, .. }
+            | RuntimeError::KeyNotFound { span, .. }
+            | RuntimeError::InvalidSlice { span, .. }
+            | RuntimeError::InvalidShift { span, .. }
+            | RuntimeError::IntegerOutOfRange { span, .. }
+            | RuntimeError::EmptyReduce { span, .. }
+            | RuntimeError::InvalidCapacity { span, .. }
+            | RuntimeError::NotMemoized { span, .. }
+            | RuntimeError::InvalidNumber { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::UndefinedVariable { name, .. } => write!(f, "Undefined variable: {}", name),
+            RuntimeError::UndeclaredAssignment { name, .. } => write!(f, "Variable not declared: {}", name),
+            RuntimeError::AssignToConstant { name, .. } => {
+                write!(f, "Cannot assign to constant variable: {}", name)
+            }
+            RuntimeError::AlreadyDeclared { name, .. } => write!(f, "Variable already declared: {}", name),
+            RuntimeError::InvalidOperands { op, left, right, .. } => {
+                write!(f, "Cannot apply `{}` to {} and {}", op, left, right)
+            }
+            RuntimeError::TypeMismatch { expected, found, .. } => {
+                write!(f, "Expected {}, found {}", expected, found)
+            }
+            RuntimeError::ArityMismatch { name, expected, found, .. } => {
+                let (count, last) = match expected {
+                    Arity::Exact(n) => (n.to_string(), *n),
+                    Arity::AtLeast(n) => (format!("at least {}", n), *n),
+                    Arity::Between(min, max) => (format!("{} to {}", min, max), *max),
+                };
+                let plural = if last == 1 { "" } else { "s" };
+                let verb = if *found == 1 { "was" } else { "were" };
+                write!(f, "{} expects {} argument{}, but {} {} given", name, count, plural, found, verb)
+            }
+            RuntimeError::StackOverflow { limit, .. } => {
+                write!(f, "Stack overflow: more than {} nested calls", limit)
+            }
+            RuntimeError::IndexOutOfBounds { index, len, .. } => {
+                write!(f, "Index {} is out of bounds for length {}", index, len)
+            }
+            RuntimeError::KeyNotFound { key, .. } => write!(f, "Key not found: {:?}", key),
+            RuntimeError::InvalidSlice { start, end, len, .. } => {
+                write!(f, "Slice {}..{} is out of bounds for length {}", start, end, len)
+            }
+            RuntimeError::InvalidShift { amount, .. } => write!(f, "Cannot shift by {} bits", amount),
+            RuntimeError::IntegerOutOfRange { value, .. } => write!(f, "{} is out of the integer range", value),
+            RuntimeError::EmptyReduce { found, .. } => write!(f, "Cannot reduce an empty {}", found),
+            RuntimeError::InvalidCapacity { capacity, .. } => {
+                write!(f, "Cache capacity must be at least 1, found {}", capacity)
+            }
+            RuntimeError::NotMemoized { name, .. } => write!(f, "{} is not memoized", name),
+            RuntimeError::InvalidNumber { text, .. } => write!(f, "Cannot read {:?} as a number", text),
+        }
+    }
+}
+
+impl Error for RuntimeError {}
+
+/// Why evaluation stopped early: a genuine error, or a `return`, `break` or
+/// `continue` unwinding to the function call or loop that will catch it.
+/// Loop jumps carry their label, if any.
+enum Unwind {
+    Error(RuntimeError),
+    Return(Value),
+    Break(Option<String>),
+    Continue(Option<String>),
+}
+
+/// Where an assignment stores its value: a variable, and the indexes and
+/// keys (each with its span) leading from it to part of its value.
+struct Place<'a> {
+    name: &'a str,
+    path: Vec<(Value, Span)>,
+}
+
+/// What a loop should do after running its body once.
+enum LoopStep {
+    Next,
+    Exit,
+}
+
+impl From<RuntimeError> for Unwind {
+    fn from(err: RuntimeError) -> Self {
+        Unwind::Error(err)
+    }
+}
+
+/// Deep enough for any sensible recursion, and a quick error for runaway
+/// recursion rather than a slow march through memory.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 2000;
+
+/// How close to the end of the stack evaluation may get before growing it,
+/// and how much to add each time.
+const STACK_RED_ZONE: usize = 256 * 1024;
+const STACK_GROWTH: usize = 4 * 1024 * 1024;
+
 pub struct Interpreter {
-    symbol_table: HashMap<String, (bool, Value)>,
+    globals: Env,
+    /// The innermost scope of whatever is running right now.
+    env: Env,
+    call_depth: usize,
+    max_call_depth: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interpreter {
     pub fn new() -> Self {
+        Self::with_args(Vec::new())
+    }
+
+    /// Creates an interpreter for a script invoked with extra command-line
+    /// arguments, which the script sees as the global list `args`.
+    pub fn with_args(args: Vec<String>) -> Self {
+        let globals = Environment::new_global();
+        let arg_values = args.into_iter().map(Value::String).collect();
+        globals.borrow_mut().declare("args", true, Value::List(Rc::new(arg_values)));
         Interpreter {
-            symbol_table: HashMap::new(),
+            env: Rc::clone(&globals),
+            globals,
+            call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
         }
     }
 
-    pub fn interpret(&mut self, expr: &Expr) -> Result<Value, String> {
-        match expr {
-            Expr::Number(n) => Ok(Value::Number(*n)),
-            Expr::Binary(left, op, right) => {
-                let left_val = self.interpret(left)?;
-                let right_val = self.interpret(right)?;
-                self.evaluate_binary_op(&left_val, op, &right_val)
+    /// Changes how deeply calls may nest before a script fails with
+    /// `RuntimeError::StackOverflow`.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    pub fn interpret(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        match self.evaluate(expr) {
+            Ok(value) => Ok(value),
+            Err(Unwind::Error(err)) => Err(err),
+            // The parser only accepts `return` inside functions, whose calls catch it.
+            Err(Unwind::Return(value)) => Ok(value),
+            Err(Unwind::Break(_) | Unwind::Continue(_)) => {
+                unreachable!("the parser only accepts `break` and `continue` inside loops")
             }
-            Expr::Var(name) => {
-                self.symbol_table
-                    .get(name)
-                    .map(|(_, value)| value.clone())
-                    .ok_or_else(|| format!("Undefined variable: {}", name))
+        }
+    }
+
+    /// Every level of a script's recursion costs several host frames here, so
+    /// the stack is grown on the heap as it runs low rather than trusting the
+    /// host to have left enough room.
+    fn evaluate(&mut self, expr: &Expr) -> Result<Value, Unwind> {
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || self.evaluate_kind(expr))
+    }
+
+    fn evaluate_kind(&mut self, expr: &Expr) -> Result<Value, Unwind> {
+        match &expr.kind {
+            ExprKind::Number(n) => Ok(Value::Number(*n)),
+            ExprKind::Integer(n) => Ok(Value::Int(*n)),
+            ExprKind::Boolean(b) => Ok(Value::Bool(*b)),
+            ExprKind::Null => Ok(Value::Null),
+            ExprKind::String(s) => Ok(Value::String(s.clone())),
+            ExprKind::Interpolation(pieces) => {
+                let mut result = String::new();
+                for piece in pieces {
+                    result.push_str(&self.evaluate(piece)?.to_string());
+                }
+                Ok(Value::String(result))
             }
-            Expr::Assign(name, expr) => {
-                if let Some((is_immut, _)) = self.symbol_table.get(name) {
-                    if *is_immut {
-                        return Err(format!("Cannot assign to constant variable: {}", name));
+            ExprKind::Unary(op, operand) => {
+                let value = self.evaluate(operand)?;
+                match (op, value) {
+                    (UnaryOp::Negate, Value::Int(n)) => {
+                        Ok(Value::Int(n.wrapping_neg()))
                     }
-                } else {
-                    return Err(format!("Variable not declared: {}", name));
+                    (UnaryOp::Negate, Value::Number(n)) => Ok(Value::Number(-n)),
+                    (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+                    (UnaryOp::BitNot, value @ (Value::Int(_) | Value::Number(_))) => {
+                        Ok(Value::Int(!integer(&value, operand.span)?))
+                    }
+                    (UnaryOp::Negate, value) => Err(RuntimeError::TypeMismatch {
+                        expected: "number",
+                        found: value.type_name(),
+                        span: operand.span,
+                    }.into()),
+                    (UnaryOp::Not, value) => Err(RuntimeError::TypeMismatch {
+                        expected: "bool",
+                        found: value.type_name(),
+                        span: operand.span,
+                    }.into()),
+                    (UnaryOp::BitNot, value) => Err(RuntimeError::TypeMismatch {
+                        expected: "integer",
+                        found: value.type_name(),
+                        span: operand.span,
+                    }.into()),
                 }
-                let value = self.interpret(expr)?;
-                self.symbol_table.insert(name.clone(), (false, value.clone()));
+            }
+            ExprKind::Binary(left, op @ (BinOp::And | BinOp::Or), right) => {
+                // Short-circuit: the right side only runs when it can change the result.
+                let left_val = self.expect_bool(left)?;
+                if left_val == (*op == BinOp::Or) {
+                    return Ok(Value::Bool(left_val));
+                }
+                Ok(Value::Bool(self.expect_bool(right)?))
+            }
+            ExprKind::Binary(left, BinOp::NullCoalesce, right) => match self.evaluate(left)? {
+                Value::Null => self.evaluate(right),
+                value => Ok(value),
+            },
+            ExprKind::Binary(left, op, right) => {
+                let left_val = self.evaluate(left)?;
+                let right_val = self.evaluate(right)?;
+                Ok(self.evaluate_binary_op(&left_val, op, &right_val, expr.span)?)
+            }
+            ExprKind::Var(name) => {
+                self.env
+                    .borrow()
+                    .get(name)
+                    .or_else(|| Builtin::from_name(name).map(|builtin| Value::Function(Rc::new(Callable::Builtin(builtin)))))
+                    .ok_or_else(|| Unwind::Error(RuntimeError::UndefinedVariable {
+                        name: name.clone(),
+                        suggestion: self.similar_name(name),
+                        span: expr.span,
+                    }))
+            }
+            ExprKind::Assign(target, value_expr) => {
+                let value = self.evaluate(value_expr)?;
+                let place = self.resolve_place(target)?;
+                self.write_place(&place, value.clone(), expr.span)?;
+                Ok(value)
+            }
+            ExprKind::CompoundAssign(target, op, value_expr) => {
+                let place = self.resolve_place(target)?;
+                let current = self.read_place(&place, target.span)?;
+                let operand = self.evaluate(value_expr)?;
+                let value = self.evaluate_binary_op(&current, op, &operand, expr.span)?;
+                self.write_place(&place, value.clone(), expr.span)?;
                 Ok(value)
             }
-            Expr::VarDecl(is_immut, name, expr) => {
-                if self.symbol_table.contains_key(name) {
-                    return Err(format!("Variable already declared: {}", name));
+            ExprKind::MaybeAssign(target, value_expr) => {
+                let place = self.resolve_place(target)?;
+                let current = match self.read_place(&place, target.span) {
+                    // A missing key counts as null. If it is not the last key, writing will report it.
+                    Err(RuntimeError::KeyNotFound { .. }) => Value::Null,
+                    current => current?,
+                };
+                if current != Value::Null {
+                    return Ok(current);
                 }
-                let value = self.interpret(expr)?;
-                self.symbol_table.insert(name.clone(), (*is_immut, value.clone()));
+                let value = self.evaluate(value_expr)?;
+                self.write_place(&place, value.clone(), expr.span)?;
                 Ok(value)
             }
-            Expr::Block(statements) => {
-                let mut last_value = Value::Number(0.0);
+            ExprKind::Nullify(target) => {
+                let place = self.resolve_place(target)?;
+                self.write_place(&place, Value::Null, expr.span)?;
+                Ok(Value::Null)
+            }
+            ExprKind::List(items) => {
+                let items = items.iter().map(|item| self.evaluate(item)).collect::<Result<_, _>>()?;
+                Ok(Value::List(Rc::new(items)))
+            }
+            ExprKind::Map(entries) => {
+                let mut map: Vec<(String, Value)> = Vec::with_capacity(entries.len());
+                for (key, value_expr) in entries {
+                    let value = self.evaluate(value_expr)?;
+                    // A repeated key keeps its first position but takes the last value.
+                    match map.iter_mut().find(|(existing, _)| existing == key) {
+                        Some((_, slot)) => *slot = value,
+                        None => map.push((key.clone(), value)),
+                    }
+                }
+                Ok(Value::Map(Rc::new(map)))
+            }
+            ExprKind::Field { target, name } => match self.evaluate(target)? {
+                Value::Map(entries) => Ok(lookup(&entries, name, expr.span)?),
+                other => {
+                    Err(RuntimeError::TypeMismatch { expected: "map", found: other.type_name(), span: target.span }.into())
+                }
+            },
+            ExprKind::Index { target, index } => {
+                let collection = self.evaluate(target)?;
+                let index = self.evaluate(index)?;
+                Ok(index_into(&collection, &index, expr.span)?)
+            }
+            ExprKind::VarDecl(is_immut, name, value_expr) => {
+                if self.env.borrow().is_declared_here(name) {
+                    return Err(RuntimeError::AlreadyDeclared { name: name.clone(), span: expr.span }.into());
+                }
+                let value = self.evaluate(value_expr)?;
+                self.env.borrow_mut().declare(name, *is_immut, value.clone());
+                Ok(value)
+            }
+            ExprKind::Block(statements) => {
+                let mut last_value = Value::Null;
                 for stmt in statements {
-                    last_value = self.interpret(stmt)?;
+                    last_value = self.evaluate(stmt)?;
                 }
                 Ok(last_value)
             }
-            Expr::PrintStmt(expr) => {
-                let value = self.interpret(expr)?;
-                match value {
-                    Value::Number(n) => {
-                        println!("{}", n);
-                        Ok(Value::Number(n))
+            ExprKind::PrintStmt(value_expr) => {
+                let value = self.evaluate(value_expr)?;
+                println!("{}", value);
+                Ok(value)
+            }
+            ExprKind::If { condition, then_branch, else_branch } => {
+                if self.expect_bool(condition)? {
+                    self.evaluate_scoped(then_branch)
+                } else if let Some(else_branch) = else_branch {
+                    self.evaluate_scoped(else_branch)
+                } else {
+                    Ok(Value::Null)
+                }
+            }
+            ExprKind::FnDecl { name, params, body } => {
+                if self.env.borrow().is_declared_here(name) {
+                    return Err(RuntimeError::AlreadyDeclared { name: name.clone(), span: expr.span }.into());
+                }
+                let function = Value::Function(Rc::new(Callable::Function(Function {
+                    name: name.clone(),
+                    params: params.clone(),
+                    body: (**body).clone(),
+                    closure: Rc::clone(&self.env),
+                })));
+                self.env.borrow_mut().declare(name, true, function.clone());
+                Ok(function)
+            }
+            ExprKind::Memoize { capacity, function } => {
+                let ExprKind::FnDecl { name, .. } = &function.kind else {
+                    unreachable!("the parser only accepts `memoize` before a function declaration")
+                };
+                let capacity = match capacity {
+                    Some(capacity) => cache_capacity(&self.evaluate(capacity)?, capacity.span)?,
+                    None => None,
+                };
+                let Value::Function(plain) = self.evaluate(function)? else {
+                    unreachable!("a function declaration evaluates to a function")
+                };
+                // Redeclared in the same scope, so recursive calls find the memoized version.
+                let memoized = memoize(plain, capacity);
+                self.env.borrow_mut().declare(name, true, memoized.clone());
+                Ok(memoized)
+            }
+            ExprKind::Lambda { params, body } => Ok(Value::Function(Rc::new(Callable::Function(Function {
+                name: "lambda".to_string(),
+                params: params.clone(),
+                body: (**body).clone(),
+                closure: Rc::clone(&self.env),
+            })))),
+            ExprKind::Call { callee, args } => {
+                let callee_val = self.evaluate(callee)?;
+                let mut arg_vals = Vec::with_capacity(args.len());
+                for arg in args {
+                    arg_vals.push(self.evaluate(arg)?);
+                }
+                Ok(self.call_value(&callee_val, arg_vals, expr.span, callee.span)?)
+            }
+            ExprKind::Return(value_expr) => {
+                let value = match value_expr {
+                    Some(value_expr) => self.evaluate(value_expr)?,
+                    None => Value::Null,
+                };
+                Err(Unwind::Return(value))
+            }
+            ExprKind::Forever { label, body } => {
+                loop {
+                    if let LoopStep::Exit = self.run_loop_body(body, label, Environment::new_child(&self.env))? {
+                        break;
+                    }
+                }
+                Ok(Value::Null)
+            }
+            ExprKind::Foreach { label, key, item, iterable, body } => {
+                let collection = self.evaluate(iterable)?;
+                for (key_val, item_val) in self.iterate(&collection, iterable.span)? {
+                    let scope = Environment::new_child(&self.env);
+                    if let Some(key) = key {
+                        scope.borrow_mut().declare(key, false, key_val);
+                    }
+                    scope.borrow_mut().declare(item, false, item_val);
+                    if let LoopStep::Exit = self.run_loop_body(body, label, scope)? {
+                        break;
+                    }
+                }
+                Ok(Value::Null)
+            }
+            ExprKind::Break(label) => Err(Unwind::Break(label.clone())),
+            ExprKind::Continue(label) => Err(Unwind::Continue(label.clone())),
+            ExprKind::Match { subject, arms } => {
+                let value = self.evaluate(subject)?;
+                for arm in arms {
+                    let mut bindings = Vec::new();
+                    if !self.match_pattern(&arm.pattern, &value, &mut bindings)? {
+                        continue;
+                    }
+
+                    let scope = Environment::new_child(&self.env);
+                    for (name, bound) in bindings {
+                        scope.borrow_mut().declare(&name, false, bound);
+                    }
+                    if let Some(guard) = &arm.guard {
+                        match self.evaluate_in(guard, Rc::clone(&scope))? {
+                            Value::Bool(true) => {}
+                            Value::Bool(false) => continue,
+                            other => {
+                                let err = RuntimeError::TypeMismatch { expected: "bool", found: other.type_name(), span: guard.span };
+                                return Err(err.into());
+                            }
+                        }
+                    }
+                    return self.evaluate_in(&arm.body, scope);
+                }
+                Ok(Value::Null)
+            }
+            ExprKind::Compare { subject, cases } => {
+                let value = self.evaluate(subject)?;
+                let mut start = None;
+                for (i, case) in cases.iter().enumerate() {
+                    if let Some(case_value) = &case.value {
+                        if self.evaluate(case_value)? == value {
+                            start = Some(i);
+                            break;
+                        }
+                    }
+                }
+                let Some(start) = start.or_else(|| cases.iter().position(|case| case.value.is_none())) else {
+                    return Ok(Value::Null);
+                };
+
+                // Every case shares one scope, since execution can fall from one into the next.
+                let scope = Environment::new_child(&self.env);
+                for statement in cases[start..].iter().flat_map(|case| &case.body) {
+                    match self.evaluate_in(statement, Rc::clone(&scope)) {
+                        Ok(_) => {}
+                        Err(Unwind::Break(None)) => break,
+                        Err(unwind) => return Err(unwind),
+                    }
+                }
+                Ok(Value::Null)
+            }
+        }
+    }
+
+    /// Finds the place an assignment target refers to, evaluating each index
+    /// once, left to right.
+    fn resolve_place<'a>(&mut self, target: &'a Expr) -> Result<Place<'a>, Unwind> {
+        let mut places = Vec::new();
+        let mut root = target;
+        while let ExprKind::Index { target, .. } | ExprKind::Field { target, .. } = &root.kind {
+            places.push(root);
+            root = target;
+        }
+        let ExprKind::Var(name) = &root.kind else {
+            unreachable!("the parser only accepts variables, indexes and fields as assignment targets")
+        };
+
+        let mut path = Vec::new();
+        for place in places.into_iter().rev() {
+            match &place.kind {
+                ExprKind::Index { index, .. } => path.push((self.evaluate(index)?, index.span)),
+                ExprKind::Field { name, .. } => path.push((Value::String(name.clone()), place.span)),
+                _ => unreachable!("only indexes and fields were collected"),
+            }
+        }
+        Ok(Place { name, path })
+    }
+
+    /// The current value of a place.
+    fn read_place(&self, place: &Place, span: Span) -> Result<Value, RuntimeError> {
+        let Place { name, path } = place;
+        let Some(mut value) = self.env.borrow().get(name) else {
+            return Err(RuntimeError::UndefinedVariable { name: name.to_string(), suggestion: self.similar_name(name), span });
+        };
+        for (index, index_span) in path {
+            value = index_into(&value, index, *index_span)?;
+        }
+        Ok(value)
+    }
+
+    /// Stores `value` in a place. Only the variable at its root needs to be mutable.
+    fn write_place(&mut self, place: &Place, value: Value, span: Span) -> Result<(), RuntimeError> {
+        let Place { name, path } = place;
+        let result = self.env.borrow_mut().update(name, |slot| store(slot, path, value));
+        match result {
+            Ok(stored) => stored,
+            Err(AssignError::Constant) => Err(RuntimeError::AssignToConstant { name: name.to_string(), span }),
+            Err(AssignError::Undeclared) => Err(RuntimeError::UndeclaredAssignment {
+                name: name.to_string(),
+                suggestion: self.similar_name(name),
+                span,
+            }),
+        }
+    }
+
+    /// Checks `value` against `pattern`, collecting the variables it binds.
+    /// On a failed match `bindings` may hold leftovers and should be discarded.
+    fn match_pattern(&mut self, pattern: &Pattern, value: &Value, bindings: &mut Vec<(String, Value)>) -> Result<bool, Unwind> {
+        match pattern {
+            Pattern::Wildcard => Ok(true),
+            Pattern::Binding(name) => {
+                bindings.push((name.clone(), value.clone()));
+                Ok(true)
+            }
+            Pattern::Literal(literal) => Ok(self.evaluate(literal)? == *value),
+            Pattern::Range { start, end, inclusive } => {
+                let start = self.evaluate(start)?;
+                let end = self.evaluate(end)?;
+                let above_start = self.evaluate_binary_op(value, &BinOp::GreaterEqual, &start, Span::default());
+                let below_end = self.evaluate_binary_op(value, if *inclusive { &BinOp::LessEqual } else { &BinOp::Less }, &end, Span::default());
+                // Values that cannot be compared with the bounds, like a string against numbers, simply don't match.
+                Ok(matches!((above_start, below_end), (Ok(Value::Bool(true)), Ok(Value::Bool(true)))))
+            }
+            Pattern::List { before, rest, after } => {
+                let Value::List(items) = value else {
+                    return Ok(false);
+                };
+                let fixed = before.len() + after.len();
+                if items.len() < fixed || (rest.is_none() && items.len() != fixed) {
+                    return Ok(false);
+                }
+
+                let rest_end = items.len() - after.len();
+                let fixed_items = items[..before.len()].iter().chain(&items[rest_end..]);
+                for (pattern, item) in before.iter().chain(after).zip(fixed_items) {
+                    if !self.match_pattern(pattern, item, bindings)? {
+                        return Ok(false);
+                    }
+                }
+                if let Some(Some(name)) = rest {
+                    bindings.push((name.clone(), Value::List(Rc::new(items[before.len()..rest_end].to_vec()))));
+                }
+                Ok(true)
+            }
+            Pattern::Map(entries) => {
+                let Value::Map(map) = value else {
+                    return Ok(false);
+                };
+                for (key, pattern) in entries {
+                    match map_get(map, key) {
+                        Some(field) if self.match_pattern(pattern, field, bindings)? => {}
+                        _ => return Ok(false),
+                    }
+                }
+                Ok(true)
+            }
+            Pattern::Or(alternatives) => {
+                let checkpoint = bindings.len();
+                for alternative in alternatives {
+                    if self.match_pattern(alternative, value, bindings)? {
+                        return Ok(true);
                     }
+                    bindings.truncate(checkpoint);
                 }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Runs one iteration of a loop body in `scope`, catching the `break`s
+    /// and `continue`s aimed at this loop: unlabeled ones, or ones naming `label`.
+    fn run_loop_body(&mut self, body: &Expr, label: &Option<String>, scope: Env) -> Result<LoopStep, Unwind> {
+        let targets_this_loop = |target: &Option<String>| target.is_none() || target == label;
+        match self.evaluate_in(body, scope) {
+            Ok(_) => Ok(LoopStep::Next),
+            Err(Unwind::Break(target)) if targets_this_loop(&target) => Ok(LoopStep::Exit),
+            Err(Unwind::Continue(target)) if targets_this_loop(&target) => Ok(LoopStep::Next),
+            Err(unwind) => Err(unwind),
+        }
+    }
+
+    /// The `(key, item)` pairs `foreach` walks over. The key is the position
+    /// of the item, or its key in a map.
+    pub(crate) fn iterate(&self, collection: &Value, span: Span) -> Result<Box<dyn Iterator<Item = (Value, Value)>>, RuntimeError> {
+        let positions = (0..).map(Value::Int);
+        match collection {
+            Value::Range(start, end) => Ok(Box::new(positions.zip((*start..*end).map(Value::Int)))),
+            Value::String(s) => {
+                let chars: Vec<Value> = s.chars().map(|c| Value::String(c.to_string())).collect();
+                Ok(Box::new(positions.zip(chars)))
+            }
+            Value::List(items) => {
+                let items = Rc::clone(items);
+                Ok(Box::new(positions.zip((0..items.len()).map(move |i| items[i].clone()))))
+            }
+            Value::Map(entries) => {
+                let entries = Rc::clone(entries);
+                Ok(Box::new((0..entries.len()).map(move |i| {
+                    let (key, value) = &entries[i];
+                    (Value::String(key.clone()), value.clone())
+                })))
+            }
+            other => Err(RuntimeError::TypeMismatch { expected: "iterable", found: other.type_name(), span }),
+        }
+    }
+
+    /// Calls `callee` with already-evaluated arguments. `span` covers the
+    /// whole call and `callee_span` just the thing being called.
+    fn call_value(&mut self, callee: &Value, args: Vec<Value>, span: Span, callee_span: Span) -> Result<Value, RuntimeError> {
+        let Value::Function(callable) = callee else {
+            return Err(RuntimeError::TypeMismatch { expected: "function", found: callee.type_name(), span: callee_span });
+        };
+        self.call(callable, args, span)
+    }
+
+    pub(crate) fn call(&mut self, callable: &Callable, args: Vec<Value>, span: Span) -> Result<Value, RuntimeError> {
+        match callable {
+            Callable::Function(function) => self.call_function(function, args, span),
+            Callable::Composed { first, second } => {
+                let intermediate = self.call(first, args, span)?;
+                self.call(second, vec![intermediate], span)
+            }
+            Callable::Partial { function, bound } => {
+                if !callable.arity().accepts(args.len()) {
+                    return Err(RuntimeError::ArityMismatch {
+                        name: callable.name(),
+                        expected: callable.arity(),
+                        found: args.len(),
+                        span,
+                    });
+                }
+                self.call(function, bound.iter().cloned().chain(args).collect(), span)
+            }
+            // The cache is only borrowed around the lookup and the insert, since
+            // a recursive function calls back into itself in between.
+            Callable::Memoized { function, cache } => {
+                if let Some(value) = cache.borrow_mut().get(&args) {
+                    return Ok(value);
+                }
+                let value = self.call(function, args.clone(), span)?;
+                cache.borrow_mut().insert(args, value.clone());
+                Ok(value)
+            }
+            Callable::Builtin(builtin) => self.call_builtin(*builtin, args, span),
+        }
+    }
+
+    fn call_function(&mut self, function: &Function, args: Vec<Value>, span: Span) -> Result<Value, RuntimeError> {
+        if args.len() != function.params.len() {
+            return Err(RuntimeError::ArityMismatch {
+                name: function.name.clone(),
+                expected: Arity::Exact(function.params.len()),
+                found: args.len(),
+                span,
+            });
+        }
+        if self.call_depth >= self.max_call_depth {
+            return Err(RuntimeError::StackOverflow { limit: self.max_call_depth, span });
+        }
+
+        // The body runs in a fresh scope inside the one the function was declared in.
+        let call_env = Environment::new_child(&function.closure);
+        for (param, arg) in function.params.iter().zip(args) {
+            call_env.borrow_mut().declare(param, false, arg);
+        }
+
+        self.call_depth += 1;
+        let result = self.evaluate_in(&function.body, call_env);
+        self.call_depth -= 1;
+
+        match result {
+            Ok(value) | Err(Unwind::Return(value)) => Ok(value),
+            Err(Unwind::Error(err)) => Err(err),
+            Err(Unwind::Break(_) | Unwind::Continue(_)) => {
+                unreachable!("the parser does not let `break` or `continue` leave a function")
             }
         }
     }
 
-    fn evaluate_binary_op(&self, left: &Value, op: &BinOp, right: &Value) -> Result<Value, String> {
+    /// Runs `expr` in a fresh scope, so anything it declares is gone afterwards.
+    fn evaluate_scoped(&mut self, expr: &Expr) -> Result<Value, Unwind> {
+        let scope = Environment::new_child(&self.env);
+        self.evaluate_in(expr, scope)
+    }
+
+    /// Runs `expr` with `env` as the current scope, then restores the previous one.
+    fn evaluate_in(&mut self, expr: &Expr, env: Env) -> Result<Value, Unwind> {
+        let previous = std::mem::replace(&mut self.env, env);
+        let result = self.evaluate(expr);
+        self.env = previous;
+        result
+    }
+
+    fn evaluate_binary_op(&self, left: &Value, op: &BinOp, right: &Value, span: Span) -> Result<Value, RuntimeError> {
+        if let (Value::Int(l), Value::Int(r)) = (left, right) {
+            if let Some(result) = integer_op(*l, op, *r) {
+                return Ok(result);
+            }
+        }
+
+        match (left.as_float(), op, right.as_float()) {
+            (Some(l), BinOp::Plus, Some(r)) => return Ok(Value::Number(l + r)),
+            (Some(l), BinOp::Minus, Some(r)) => return Ok(Value::Number(l - r)),
+            (Some(l), BinOp::Multiply, Some(r)) => return Ok(Value::Number(l * r)),
+            (Some(l), BinOp::Divide, Some(r)) => {
+                return if r == 0.0 { Ok(left.clone()) } else { Ok(Value::Number(l / r)) };
+            }
+            (Some(l), BinOp::Mod, Some(r)) => {
+                return if r == 0.0 { Ok(left.clone()) } else { Ok(Value::Number(l % r)) };
+            }
+            (Some(l), BinOp::Power, Some(r)) => return Ok(Value::Number(l.powf(r))),
+            (Some(l), BinOp::Greater, Some(r)) => return Ok(Value::Bool(l > r)),
+            (Some(l), BinOp::GreaterEqual, Some(r)) => return Ok(Value::Bool(l >= r)),
+            (Some(l), BinOp::Less, Some(r)) => return Ok(Value::Bool(l < r)),
+            (Some(l), BinOp::LessEqual, Some(r)) => return Ok(Value::Bool(l <= r)),
+            (Some(_), BinOp::Range | BinOp::RangeInclusive, Some(_)) => {
+                let start = integer(left, span)?;
+                let end = integer(right, span)?;
+                let end = if *op == BinOp::RangeInclusive { end.saturating_add(1) } else { end };
+                return Ok(Value::Range(start, end));
+            }
+            (Some(_), BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::ShiftLeft | BinOp::ShiftRight, Some(_)) => {
+                return bitwise_op(integer(left, span)?, op, integer(right, span)?, span);
+            }
+            _ => {}
+        }
+
         match (left, op, right) {
-            (Value::Number(l), BinOp::Plus, Value::Number(r)) => Ok(Value::Number(l + r)),
-            (Value::Number(l), BinOp::Minus, Value::Number(r)) => Ok(Value::Number(l - r)),
-            (Value::Number(l), BinOp::Multiply, Value::Number(r)) => Ok(Value::Number(l * r)),
-            (Value::Number(l), BinOp::Divide, Value::Number(r)) => {
-                if *r == 0.0 { Ok(Value::Number(*l)) } else { Ok(Value::Number(l / r)) }
+            (Value::String(l), BinOp::Plus | BinOp::Concat, Value::String(r)) => {
+                Ok(Value::String(format!("{}{}", l, r)))
+            }
+            (Value::List(l), BinOp::Concat, Value::List(r)) => {
+                Ok(Value::List(Rc::new(l.iter().chain(r.iter()).cloned().collect())))
+            }
+            (Value::Function(first), BinOp::ShiftRight, Value::Function(second))
+            | (Value::Function(second), BinOp::ShiftLeft, Value::Function(first)) => {
+                Ok(Value::Function(Rc::new(Callable::Composed { first: Rc::clone(first), second: Rc::clone(second) })))
+            }
+            (_, BinOp::Equal, _) => Ok(Value::Bool(left == right)),
+            (_, BinOp::NotEqual, _) => Ok(Value::Bool(left != right)),
+            (Value::String(l), BinOp::Greater, Value::String(r)) => Ok(Value::Bool(l > r)),
+            (Value::String(l), BinOp::GreaterEqual, Value::String(r)) => Ok(Value::Bool(l >= r)),
+            (Value::String(l), BinOp::Less, Value::String(r)) => Ok(Value::Bool(l < r)),
+            (Value::String(l), BinOp::LessEqual, Value::String(r)) => Ok(Value::Bool(l <= r)),
+            _ => Err(RuntimeError::InvalidOperands {
+                op: op.clone(),
+                left: left.type_name(),
+                right: right.type_name(),
+                span,
+            }),
+        }
+    }
+
+    fn expect_bool(&mut self, expr: &Expr) -> Result<bool, Unwind> {
+        match self.evaluate(expr)? {
+            Value::Bool(b) => Ok(b),
+            value => Err(RuntimeError::TypeMismatch { expected: "bool", found: value.type_name(), span: expr.span }.into()),
+        }
+    }
+
+    fn similar_name(&self, name: &str) -> Option<String> {
+        let names = self.env.borrow().names();
+        closest_match(name, names.iter().map(String::as_str)).map(str::to_string)
+    }
+
+    /// Looks up a global binding.
+    pub fn get_variable(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().get(name)
+    }
+
+    /// Lists every global binding as `(name, is_const, value)`, sorted by name.
+    pub fn variables(&self) -> Vec<(String, bool, Value)> {
+        let mut vars = self.globals.borrow().bindings();
+        vars.sort_by(|a, b| a.0.cmp(&b.0));
+        vars
+    }
+}
+
+/// Converts a value that must be a whole number, such as a range bound or
+/// an index, to an integer. Floats are accepted when they have no fraction.
+pub(crate) fn integer(value: &Value, span: Span) -> Result<i64, RuntimeError> {
+    match value {
+        Value::Int(n) => Ok(*n),
+        Value::Number(n) => match whole(*n) {
+            Some(i) => Ok(i),
+            None if n.fract() == 0.0 || n.is_infinite() => Err(RuntimeError::IntegerOutOfRange { value: *n, span }),
+            None => Err(RuntimeError::TypeMismatch { expected: "integer", found: "number with a fraction", span }),
+        },
+        other => Err(RuntimeError::TypeMismatch { expected: "integer", found: other.type_name(), span }),
+    }
+}
+
+/// The integer a float is exactly equal to, if there is one.
+fn whole(n: f64) -> Option<i64> {
+    // `i64::MAX as f64` rounds up to 2^63, which is already out of range.
+    (n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64).then_some(n as i64)
+}
+
+/// Arithmetic and comparison on two integers, wrapping around on overflow
+/// like the bitwise operators do, so hashes and masks stay integers. `None`
+/// when the result is not an integer (`/` always gives a float, and so does
+/// a negative power), so the caller can fall back to floats.
+fn integer_op(l: i64, op: &BinOp, r: i64) -> Option<Value> {
+    let result = match op {
+        BinOp::Plus => Value::Int(l.wrapping_add(r)),
+        BinOp::Minus => Value::Int(l.wrapping_sub(r)),
+        BinOp::Multiply => Value::Int(l.wrapping_mul(r)),
+        // Like division, taking the remainder by zero leaves the number alone.
+        BinOp::Mod if r == 0 => Value::Int(l),
+        BinOp::Mod => Value::Int(l.wrapping_rem(r)),
+        BinOp::Power if r >= 0 => Value::Int(wrapping_pow(l, r as u64)),
+        BinOp::Greater => Value::Bool(l > r),
+        BinOp::GreaterEqual => Value::Bool(l >= r),
+        BinOp::Less => Value::Bool(l < r),
+        BinOp::LessEqual => Value::Bool(l <= r),
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Exponentiation by squaring, for exponents too large for `i64::wrapping_pow`.
+fn wrapping_pow(mut base: i64, mut exponent: u64) -> i64 {
+    let mut result: i64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// `&`, `|`, `^`, `<<` and `>>` on the 64-bit two's complement
+/// representation. `>>` keeps the sign.
+fn bitwise_op(l: i64, op: &BinOp, r: i64, span: Span) -> Result<Value, RuntimeError> {
+    let shift = || u32::try_from(r).ok().filter(|r| *r < i64::BITS).ok_or(RuntimeError::InvalidShift { amount: r, span });
+    let result = match op {
+        BinOp::BitAnd => l & r,
+        BinOp::BitOr => l | r,
+        BinOp::BitXor => l ^ r,
+        BinOp::ShiftLeft => l << shift()?,
+        BinOp::ShiftRight => l >> shift()?,
+        _ => unreachable!("`{}` is not a bitwise operator", op),
+    };
+    Ok(Value::Int(result))
+}
+
+/// `collection[index]`, for lists, maps and strings. Strings are indexed by character.
+fn index_into(collection: &Value, index: &Value, span: Span) -> Result<Value, RuntimeError> {
+    match (collection, index) {
+        (Value::Map(entries), Value::String(key)) => lookup(entries, key, span),
+        (Value::Map(_), _) => Err(RuntimeError::TypeMismatch { expected: "string key", found: index.type_name(), span }),
+        (Value::List(items), Value::Range(start, end)) => {
+            let (start, end) = slice_bounds(*start, *end, items.len(), span)?;
+            Ok(Value::List(Rc::new(items[start..end].to_vec())))
+        }
+        (Value::List(items), _) => Ok(items[position(index, items.len(), span)?].clone()),
+        (Value::String(s), Value::Range(start, end)) => {
+            let chars: Vec<char> = s.chars().collect();
+            let (start, end) = slice_bounds(*start, *end, chars.len(), span)?;
+            Ok(Value::String(chars[start..end].iter().collect()))
+        }
+        (Value::String(s), _) => {
+            let chars: Vec<char> = s.chars().collect();
+            Ok(Value::String(chars[position(index, chars.len(), span)?].to_string()))
+        }
+        (other, _) => Err(RuntimeError::TypeMismatch { expected: "list, map or string", found: other.type_name(), span }),
+    }
+}
+
+/// Replaces the element `path` leads to inside `slot`, or `slot` itself
+/// when the path is empty. Each step of the path is an index or key and its
+/// span. A missing key is added when it is the last step.
+fn store(slot: &mut Value, path: &[(Value, Span)], value: Value) -> Result<(), RuntimeError> {
+    let Some(((index, span), rest)) = path.split_first() else {
+        *slot = value;
+        return Ok(());
+    };
+    match slot {
+        Value::List(items) => {
+            let position = position(index, items.len(), *span)?;
+            store(&mut Rc::make_mut(items)[position], rest, value)
+        }
+        Value::Map(entries) => {
+            let Value::String(key) = index else {
+                return Err(RuntimeError::TypeMismatch { expected: "string key", found: index.type_name(), span: *span });
+            };
+            match entries.iter().position(|(existing, _)| existing == key) {
+                Some(i) => store(&mut Rc::make_mut(entries)[i].1, rest, value),
+                None if rest.is_empty() => {
+                    Rc::make_mut(entries).push((key.clone(), value));
+                    Ok(())
+                }
+                None => Err(key_not_found(entries, key, *span)),
             }
-            (Value::Number(l), BinOp::Mod, Value::Number(r)) => {
-                if *r == 0.0 { Ok(Value::Number(*l)) } else { Ok(Value::Number(l % r)) }
+        }
+        other => Err(RuntimeError::TypeMismatch { expected: "list or map", found: other.type_name(), span: *span }),
+    }
+}
+
+/// Turns an index into a position in a sequence of `len` elements, counting
+/// negative indexes back from the end.
+fn position(index: &Value, len: usize, span: Span) -> Result<usize, RuntimeError> {
+    if !matches!(index, Value::Int(_) | Value::Number(_)) {
+        return Err(RuntimeError::TypeMismatch { expected: "integer or range", found: index.type_name(), span });
+    }
+    let index = integer(index, span)?;
+    let position = if index < 0 { index + len as i64 } else { index };
+    if (0..len as i64).contains(&position) {
+        Ok(position as usize)
+    } else {
+        Err(RuntimeError::IndexOutOfBounds { index, len, span })
+    }
+}
+
+/// Turns a range into the bounds of a slice of a sequence of `len`
+/// elements. Negative bounds count back from the end.
+fn slice_bounds(start: i64, end: i64, len: usize, span: Span) -> Result<(usize, usize), RuntimeError> {
+    let from_end = |bound: i64| if bound < 0 { bound + len as i64 } else { bound };
+    let (from, to) = (from_end(start), from_end(end));
+    if 0 <= from && from <= to && to <= len as i64 {
+        Ok((from as usize, to as usize))
+    } else {
+        Err(RuntimeError::InvalidSlice { start, end, len, span })
+    }
+}
+
+fn map_get<'a>(entries: &'a [(String, Value)], key: &str) -> Option<&'a Value> {
+    entries.iter().find(|(existing, _)| existing == key).map(|(_, value)| value)
+}
+
+/// The value stored under `key`, or an error suggesting a similar key.
+fn lookup(entries: &[(String, Value)], key: &str, span: Span) -> Result<Value, RuntimeError> {
+    map_get(entries, key).cloned().ok_or_else(|| key_not_found(entries, key, span))
+}
+
+fn key_not_found(entries: &[(String, Value)], key: &str, span: Span) -> RuntimeError {
+    let suggestion = closest_match(key, entries.iter().map(|(existing, _)| existing.as_str())).map(str::to_string);
+    RuntimeError::KeyNotFound { key: key.to_string(), suggestion, span }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::Parser;
+
+    fn run(interpreter: &mut Interpreter, source: &str) -> Result<Value, RuntimeError> {
+        let tokens = tokenize(source).expect("source should lex");
+        let ast = Parser::new(tokens).parse().expect("source should parse");
+        interpreter.interpret(&ast)
+    }
+
+    fn hash(value: &Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    const COUNTDOWN: &str = "fn f(n) { whether (n == 0) { return 0; } 1 + f(n - 1) }";
+
+    // Test threads get a small stack, far smaller than the CLI's.
+    #[test]
+    fn deep_recursion_fits_on_a_small_host_stack() {
+        let mut interpreter = Interpreter::new();
+        let source = format!("{} f({});", COUNTDOWN, DEFAULT_MAX_CALL_DEPTH - 1);
+        assert_eq!(run(&mut interpreter, &source), Ok(Value::Int(DEFAULT_MAX_CALL_DEPTH as i64 - 1)));
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps_instead_of_turning_into_floats() {
+        let mut interpreter = Interpreter::new();
+        let source = "mutate h = 5381; foreach _ in 0..20 { h = h * 33 + 7; } h & 0xffff";
+        assert!(matches!(run(&mut interpreter, source), Ok(Value::Int(_))));
+        assert_eq!(run(&mut interpreter, "9223372036854775807 + 1"), Ok(Value::Int(i64::MIN)));
+        assert_eq!(run(&mut interpreter, "(1 << 62) * 4"), Ok(Value::Int(0)));
+        assert_eq!(run(&mut interpreter, "0xffffffffffffffff"), Ok(Value::Int(-1)));
+    }
+
+    #[test]
+    fn whole_floats_outside_the_integer_range_are_rejected() {
+        let mut interpreter = Interpreter::new();
+        let err = run(&mut interpreter, "2.0 ** 63 & 1").unwrap_err();
+        assert!(matches!(err, RuntimeError::IntegerOutOfRange { .. }));
+        assert_eq!(run(&mut interpreter, "2.0 ** 62 & 1"), Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn partials_show_their_bound_arguments_and_remaining_arity() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "fn add(a, b) { a + b }").unwrap();
+        assert_eq!(run(&mut interpreter, "partial(add)").unwrap().to_string(), "<fn partial(add)/2>");
+        assert_eq!(run(&mut interpreter, "partial(add, \"x\")").unwrap().to_string(), "<fn partial(add, \"x\")/1>");
+    }
+
+    #[test]
+    fn integers_and_floats_are_equal_only_when_exactly_equal() {
+        let (int, float) = (Value::Int(1 << 53), Value::Number(2f64.powi(53)));
+        assert_eq!(int, float);
+        assert_eq!(hash(&int), hash(&float));
+        assert_ne!(Value::Int((1 << 53) + 1), float);
+    }
+
+    #[test]
+    fn call_depth_limit_is_configurable() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_max_call_depth(10);
+        let err = run(&mut interpreter, &format!("{} f(20);", COUNTDOWN)).unwrap_err();
+        assert!(matches!(err, RuntimeError::StackOverflow { limit: 10, .. }));
+    }
+
+    #[test]
+    fn arity_mismatch_agrees_with_both_counts() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "fn one(a) { a } fn two(a, b) { a + b }").unwrap();
+        let message = |interpreter: &mut Interpreter, source| run(interpreter, source).unwrap_err().to_string();
+        assert_eq!(message(&mut interpreter, "two(1)"), "two expects 2 arguments, but 1 was given");
+        assert_eq!(message(&mut interpreter, "one(1, 2)"), "one expects 1 argument, but 2 were given");
+        assert_eq!(message(&mut interpreter, "one()"), "one expects 1 argument, but 0 were given");
+    }
+
+    #[test]
+    fn a_block_can_shadow_an_outer_binding() {
+        let mut interpreter = Interpreter::new();
+        let source = "const x = 1; mutate seen = 0; whether (true) { const x = 2; seen = x; } x * 10 + seen";
+        assert_eq!(run(&mut interpreter, source), Ok(Value::Int(12)));
+    }
+
+    #[test]
+    fn closures_share_the_bindings_they_capture() {
+        let mut interpreter = Interpreter::new();
+        let source = "
+            fn make_counter() {
+                mutate n = 0;
+                fn inc() { n = n + 1; n }
+                fn peek() { n }
+                [inc, peek]
             }
+            const counter = make_counter();
+            const other = make_counter();
+            counter[0]();
+            counter[0]();
+            other[0]();
+            [counter[1](), other[1]()]
+        ";
+        assert_eq!(run(&mut interpreter, source).unwrap().to_string(), "[2, 1]");
+    }
+
+    #[test]
+    fn redeclaring_is_only_an_error_in_the_same_scope() {
+        let mut interpreter = Interpreter::new();
+        assert!(run(&mut interpreter, "const x = 1; whether (true) { const x = 2; }").is_ok());
+        let err = run(&mut interpreter, "mutate x = 3;").unwrap_err();
+        assert!(matches!(err, RuntimeError::AlreadyDeclared { ref name, .. } if name == "x"));
+    }
+
+    #[test]
+    fn constants_cannot_be_assigned_from_an_inner_scope() {
+        let mut interpreter = Interpreter::new();
+        let err = run(&mut interpreter, "const x = 1; fn f() { whether (true) { x = 2; } } f();").unwrap_err();
+        assert!(matches!(err, RuntimeError::AssignToConstant { .. }));
+        assert_eq!(run(&mut interpreter, "x"), Ok(Value::Int(1)));
+    }
+
+    #[test]
+    fn match_tries_literals_alternatives_and_ranges_in_order() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "fn size(n) { match n { 0 => \"none\", 1 | 2 => \"few\", 3..=9 => \"some\", 10..20 => \"many\", _ => \"lots\" } }").unwrap();
+        let size = |interpreter: &mut Interpreter, n| run(interpreter, &format!("size({})", n)).unwrap().to_string();
+        assert_eq!(size(&mut interpreter, 0), "none");
+        assert_eq!(size(&mut interpreter, 2), "few");
+        assert_eq!(size(&mut interpreter, 9), "some");
+        assert_eq!(size(&mut interpreter, 19), "many");
+        assert_eq!(size(&mut interpreter, 20), "lots");
+    }
+
+    #[test]
+    fn list_and_map_patterns_take_values_apart() {
+        let mut interpreter = Interpreter::new();
+        let list = "match [1, 2, 3, 4] { [a, ..rest, z] => [a, rest, z] }";
+        assert_eq!(run(&mut interpreter, list).unwrap().to_string(), "[1, [2, 3], 4]");
+        assert_eq!(run(&mut interpreter, "match [1] { [a, b, ..rest] => 1, [a] => a * 10 }"), Ok(Value::Int(10)));
+        let map = "match #{ \"name\": \"ann\", \"age\": 3 } { #{ name, \"age\": 3 } => name, _ => null }";
+        assert_eq!(run(&mut interpreter, map), Ok(Value::String("ann".to_string())));
+        assert_eq!(run(&mut interpreter, "match #{} { #{ name } => name, _ => 0 }"), Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn a_failing_guard_moves_on_to_the_next_arm() {
+        let mut interpreter = Interpreter::new();
+        let source = "match 5 { n whether n > 10 => \"big\", n => n }";
+        assert_eq!(run(&mut interpreter, source), Ok(Value::Int(5)));
+    }
+
+    #[test]
+    fn negative_indexes_count_from_the_end() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "const xs = [1, 2, 3];").unwrap();
+        assert_eq!(run(&mut interpreter, "xs[-1]"), Ok(Value::Int(3)));
+        assert_eq!(run(&mut interpreter, "xs[-3]"), Ok(Value::Int(1)));
+        assert_eq!(run(&mut interpreter, "xs[1..3]").unwrap().to_string(), "[2, 3]");
+    }
+
+    #[test]
+    fn indexing_and_slicing_past_the_end_are_errors() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "const xs = [1, 2, 3];").unwrap();
+        for source in ["xs[3]", "xs[-4]"] {
+            let err = run(&mut interpreter, source).unwrap_err();
+            assert!(matches!(err, RuntimeError::IndexOutOfBounds { len: 3, .. }), "{}: {:?}", source, err);
+        }
+        for source in ["xs[2..1]", "xs[0..4]"] {
+            let err = run(&mut interpreter, source).unwrap_err();
+            assert!(matches!(err, RuntimeError::InvalidSlice { len: 3, .. }), "{}: {:?}", source, err);
         }
     }
 
-    pub fn get_variable(&self, name: &str) -> Option<&Value> {
-        self.symbol_table.get(name).map(|(_, value)| value)
+    #[test]
+    fn changing_a_nested_element_leaves_copies_alone() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "mutate xs = [[1, 2], [3]]; const ys = xs; xs[0][1] = 9;").unwrap();
+        assert_eq!(run(&mut interpreter, "xs").unwrap().to_string(), "[[1, 9], [3]]");
+        assert_eq!(run(&mut interpreter, "ys").unwrap().to_string(), "[[1, 2], [3]]");
+    }
+
+    #[test]
+    fn maps_keep_insertion_order() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "mutate m = #{ b: 1, \"a\": 2 }; m.c = 3; m.b = 4;").unwrap();
+        assert_eq!(run(&mut interpreter, "m").unwrap().to_string(), r#"#{"b": 4, "a": 2, "c": 3}"#);
+        let keys = "mutate keys = \"\"; foreach k, v in m { keys = keys ++ k; } keys";
+        assert_eq!(run(&mut interpreter, keys), Ok(Value::String("bac".to_string())));
+    }
+
+    #[test]
+    fn map_equality_and_hashing_ignore_order() {
+        let mut interpreter = Interpreter::new();
+        let first = run(&mut interpreter, "#{ a: 1, b: [2] }").unwrap();
+        let second = run(&mut interpreter, "#{ b: [2], a: 1.0 }").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(hash(&first), hash(&second));
+        assert_ne!(first, run(&mut interpreter, "#{ a: 1 }").unwrap());
+        assert_eq!(run(&mut interpreter, "#{ a: 1, b: 2 } == #{ b: 2, a: 1 }"), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn pipe_passes_its_value_first_unless_the_call_is_parenthesized() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "fn f(x) { x + 1 } fn g(x, y) { x - y } fn h(n) { (x) -> x * n }").unwrap();
+        assert_eq!(run(&mut interpreter, "10 |> f"), Ok(Value::Int(11)));
+        assert_eq!(run(&mut interpreter, "10 |> g(2)"), Ok(Value::Int(8)));
+        assert_eq!(run(&mut interpreter, "10 |> (h(3))"), Ok(Value::Int(30)));
     }
 }
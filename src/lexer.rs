@@ -1,6 +1,15 @@
+use std::error::Error;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::span::Span;
+
 #[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Token {
-    Number(f64),
+    Number(f64), // Float literal: 1.5, 2e10
+    Integer(i64), // Integer literal: 42, 0xff, 0b1010, 0o17
     Plus,
     Minus,
     Asterisk,
@@ -18,6 +27,9 @@ pub enum Token {
     EqualGreater,
     And,
     Or,
+    Bar, // | Bitwise or, and pattern alternatives
+    Ampersand, // & Bitwise and
+    Tilde, // ~ Bitwise not
     Semicolon,
     LBracket,
     RBracket,
@@ -28,6 +40,7 @@ pub enum Token {
     Whether, // If
     Otherwise, // Else
     Compare, // Switch-like statement
+    Case, // Branch of a compare
     Fn, // Function
     Foreach, // For loop
     Forever, // Infinite loop
@@ -41,7 +54,9 @@ pub enum Token {
     Print,
     Ident(String),
     String(String),
+    Template(Vec<TemplatePart>), // String containing ${...} interpolations
     Boolean(bool),
+    Null, // The absence of a value
     LeftParen,
     RightParen,
     Map, // Map function
@@ -63,10 +78,9 @@ pub enum Token {
     Catch, // Catch errors
     Backtick, // ` Backtick for function composition
     PipeForward, // |>
-    ComposeLeft, // <<
-    ComposeRight, // >>
+    ShiftLeft, // <<
+    ShiftRight, // >>
     LambdaArrow, // ->
-    Bind, // <-
     NullCoalesce, // ??
     Concat, // ++
     Exponent, // **
@@ -75,210 +89,390 @@ pub enum Token {
     MaybeAssign, // ?=
     ModAssign, // %=
     DivAssign, // /=
+    PlusAssign, // +=
+    MinusAssign, // -=
+    MulAssign, // *=
+    PowAssign, // **=
+    ConcatAssign, // ++=
     Xor, // ^
     Dot,
+    DotDot, // .. Exclusive range
+    DotDotEqual, // ..= Inclusive range
+    Colon, // Loop labels
+    In, // foreach item in collection
     Hash,
 
     EOF,
 }
 
-pub fn tokenize(input: &str) -> Vec<Token> {
-    let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
+impl fmt::Display for Token {
+    /// Formats the token the way it is spelled in source, for error messages.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Token::Number(n) => return write!(f, "{}", n),
+            Token::Integer(n) => return write!(f, "{}", n),
+            Token::Ident(name) => return write!(f, "{}", name),
+            Token::String(string) => return write!(f, "{:?}", string),
+            Token::Template(_) => "string template",
+            Token::Boolean(b) => return write!(f, "{}", b),
+            Token::Null => "null",
+            Token::EOF => "end of input",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Asterisk => "*",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Equal => "=",
+            Token::Comma => ",",
+            Token::Bang => "!",
+            Token::EqualEqual => "==",
+            Token::BangEqual => "!=",
+            Token::Greater => ">",
+            Token::GreaterEqual => ">=",
+            Token::Less => "<",
+            Token::LessEqual => "<=",
+            Token::EqualGreater => "=>",
+            Token::And => "&&",
+            Token::Or => "||",
+            Token::Bar => "|",
+            Token::Ampersand => "&",
+            Token::Tilde => "~",
+            Token::Semicolon => ";",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::ConstVar => "const",
+            Token::Mutate => "mutate",
+            Token::Whether => "whether",
+            Token::Otherwise => "otherwise",
+            Token::Compare => "compare",
+            Token::Case => "case",
+            Token::Fn => "fn",
+            Token::Foreach => "foreach",
+            Token::Forever => "forever",
+            Token::Return => "return",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::Change => "change",
+            Token::Import => "import",
+            Token::Export => "export",
+            Token::Nullify => "nullify",
+            Token::Print => "print",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::Map => "map",
+            Token::Filter => "filter",
+            Token::Reduce => "reduce",
+            Token::Fold => "fold",
+            Token::Zip => "zip",
+            Token::Lazy => "lazy",
+            Token::Memoize => "memoize",
+            Token::Lambda => "lambda",
+            Token::Compose => "compose",
+            Token::Pipe => "pipe",
+            Token::Partial => "partial",
+            Token::LetRec => "letRec",
+            Token::Match => "match",
+            Token::Defer => "defer",
+            Token::Await => "await",
+            Token::Yield => "yield",
+            Token::Catch => "catch",
+            Token::Backtick => "`",
+            Token::PipeForward => "|>",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+            Token::LambdaArrow => "->",
+            Token::NullCoalesce => "??",
+            Token::Concat => "++",
+            Token::Exponent => "**",
+            Token::TypeDeclaration => "::",
+            Token::Assign => ":=",
+            Token::MaybeAssign => "?=",
+            Token::ModAssign => "%=",
+            Token::DivAssign => "/=",
+            Token::PlusAssign => "+=",
+            Token::MinusAssign => "-=",
+            Token::MulAssign => "*=",
+            Token::PowAssign => "**=",
+            Token::ConcatAssign => "++=",
+            Token::Xor => "^",
+            Token::Dot => ".",
+            Token::DotDot => "..",
+            Token::DotDotEqual => "..=",
+            Token::Colon => ":",
+            Token::In => "in",
+            Token::Hash => "#",
+        };
+        f.write_str(text)
+    }
+}
 
-    while let Some(c) = chars.next() {
-        match c {
-            '-' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '>' {
-                        tokens.push(Token::LambdaArrow);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::Minus);
-                    }
-                } else {
-                    tokens.push(Token::Minus);
-                }
-            }
-            '*' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '*' {
-                        tokens.push(Token::Exponent);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::Asterisk);
-                    }
-                } else {
-                    tokens.push(Token::Asterisk);
-                }
+/// A piece of an interpolated string such as `"total: ${a + b}"`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TemplatePart {
+    Text(String),
+    /// The tokens between `${` and `}`, ending with `Token::EOF`.
+    Code(Vec<SpannedToken>),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TriviaKind {
+    LineComment, // // ...
+    BlockComment, // /* ... */
+    DocComment, // /// ...
+}
+
+/// A comment the parser skips over but tools such as a formatter or doc
+/// generator want to keep. `text` is the comment without its delimiters.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+
+/// A token together with the region of source it was read from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+    /// Comments between the previous token and this one.
+    pub leading_trivia: Vec<Trivia>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexError {
+    UnexpectedCharacter { character: char, span: Span },
+    InvalidNumber { literal: String, span: Span },
+    NumberOutOfRange { literal: String, span: Span },
+    UnterminatedComment { span: Span },
+    UnterminatedString { span: Span },
+    /// `sequence` is the escape as written, including the backslash.
+    InvalidEscape { sequence: String, span: Span },
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnexpectedCharacter { span, .. }
+            | LexError::InvalidNumber { span, .. }
+            | LexError::NumberOutOfRange { span, .. }
+            | LexError::UnterminatedComment { span }
+            | LexError::UnterminatedString { span }
+            | LexError::InvalidEscape { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexError::UnexpectedCharacter { character, .. } => {
+                write!(f, "Unexpected character: {:?}", character)
             }
-            '/' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '=' {
-                        tokens.push(Token::DivAssign);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::Slash);
-                    }
-                } else {
-                    tokens.push(Token::Slash);
-                }
+            LexError::InvalidNumber { literal, .. } => write!(f, "Invalid number: {}", literal),
+            LexError::NumberOutOfRange { literal, .. } => {
+                write!(f, "Integer literal is too large: {}", literal)
             }
-            '%' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '=' {
-                        tokens.push(Token::ModAssign);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::Percent);
-                    }
+            LexError::UnterminatedComment { .. } => write!(f, "Unterminated block comment"),
+            LexError::UnterminatedString { .. } => write!(f, "Unterminated string"),
+            LexError::InvalidEscape { sequence, .. } => write!(f, "Invalid escape sequence: {}", sequence),
+        }
+    }
+}
+
+impl Error for LexError {}
+
+/// Walks the input one character at a time while keeping track of the
+/// byte offset, line and column of the next character.
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(input: &'a str) -> Self {
+        Cursor { chars: input.chars().peekable(), offset: 0, line: 1, column: 1 }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    /// The character after the next one.
+    fn peek_second(&self) -> Option<char> {
+        self.chars.clone().nth(1)
+    }
+
+    /// Consumes the next character if it is `expected`.
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(&expected) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// An empty span at the current position.
+    fn mark(&self) -> Span {
+        Span::new(self.offset, self.offset, self.line, self.column)
+    }
+
+    fn span_from(&self, start: Span) -> Span {
+        Span { end: self.offset, ..start }
+    }
+}
+
+/// Splits `input` into tokens, ending with `Token::EOF`.
+///
+/// Lexing carries on past bad characters so that every error in the input
+/// is reported at once.
+pub fn tokenize(input: &str) -> Result<Vec<SpannedToken>, Vec<LexError>> {
+    let mut chars = Cursor::new(input);
+    let mut errors = Vec::new();
+    let (tokens, _) = lex_tokens(&mut chars, &mut errors, false);
+
+    if errors.is_empty() { Ok(tokens) } else { Err(errors) }
+}
+
+/// Lexes up to the end of input or, when `in_interpolation` is set, up to
+/// and including the `}` that closes a `${`. The tokens always end with
+/// `Token::EOF`; the flag says whether a closing `}` was found.
+fn lex_tokens(chars: &mut Cursor, errors: &mut Vec<LexError>, in_interpolation: bool) -> (Vec<SpannedToken>, bool) {
+    let mut tokens = Vec::new();
+    let mut trivia = Vec::new();
+    let mut depth = 0;
+
+    loop {
+        let start = chars.mark();
+        let Some(c) = chars.next() else { break };
+
+        let token = match c {
+            '-' if chars.eat('>') => Token::LambdaArrow,
+            '-' if chars.eat('=') => Token::MinusAssign,
+            '-' => Token::Minus,
+            '*' if chars.eat('*') => if chars.eat('=') { Token::PowAssign } else { Token::Exponent },
+            '*' if chars.eat('=') => Token::MulAssign,
+            '*' => Token::Asterisk,
+            '/' if chars.eat('/') => {
+                let kind = if chars.peek() == Some(&'/') {
+                    chars.next();
+                    // `////...` is a separator line, not documentation.
+                    if chars.peek() == Some(&'/') { TriviaKind::LineComment } else { TriviaKind::DocComment }
                 } else {
-                    tokens.push(Token::Percent);
-                }
-            }
-            '`' => tokens.push(Token::Backtick),
-
-            ',' => tokens.push(Token::Comma),
-            ';' => tokens.push(Token::Semicolon),
-            '[' => tokens.push(Token::LBracket),
-            ']' => tokens.push(Token::RBracket),
-            '{' => tokens.push(Token::LBrace),
-            '}' => tokens.push(Token::RBrace),
-            '(' => tokens.push(Token::LeftParen),
-            ')' => tokens.push(Token::RightParen),
-            '^' => tokens.push(Token::Xor),
-            '.' => tokens.push(Token::Dot),
-            '#' => tokens.push(Token::Hash),
-            '?' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '?' {
-                        tokens.push(Token::NullCoalesce);
-                        chars.next();
-                    } else if *c == '=' {
-                        tokens.push(Token::MaybeAssign);
-                        chars.next();
+                    TriviaKind::LineComment
+                };
+                let mut text = String::new();
+                while let Some(c) = chars.peek() {
+                    if *c == '\n' {
+                        break;
                     }
-                } else {
-                    tokens.push(Token::MaybeAssign);
+                    text.push(*c);
+                    chars.next();
                 }
+                trivia.push(Trivia { kind, text, span: chars.span_from(start) });
+                continue;
             }
-            '=' => {
-                if let Some(c) = chars.peek() {
-                    match *c {
-                        '=' => {
-                            tokens.push(Token::EqualEqual);
-                            chars.next();
-                        }
-                        '>' => {
-                            tokens.push(Token::EqualGreater);
-                            chars.next();
-                        }
-                        _ => tokens.push(Token::Equal),
+            '/' if chars.eat('*') => {
+                match block_comment(chars) {
+                    Some(text) => {
+                        trivia.push(Trivia { kind: TriviaKind::BlockComment, text, span: chars.span_from(start) });
                     }
-                } else {
-                    tokens.push(Token::Equal);
+                    None => errors.push(LexError::UnterminatedComment { span: chars.span_from(start) }),
                 }
+                continue;
             }
-            ':' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '=' {
-                        tokens.push(Token::Assign);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::TypeDeclaration);
-                        chars.next();
-                    }
-                }
+            '/' => if chars.eat('=') { Token::DivAssign } else { Token::Slash },
+            '%' => if chars.eat('=') { Token::ModAssign } else { Token::Percent },
+            '`' => Token::Backtick,
+
+            ',' => Token::Comma,
+            ';' => Token::Semicolon,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '{' => {
+                depth += 1;
+                Token::LBrace
             }
-            '+' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '+' {
-                        tokens.push(Token::Concat);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::Plus);
-                    }
-                } else {
-                    tokens.push(Token::Plus);
-                }
+            '}' if in_interpolation && depth == 0 => {
+                tokens.push(SpannedToken { token: Token::EOF, span: start, leading_trivia: trivia });
+                return (tokens, true);
             }
-            '&' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '&' {
-                        tokens.push(Token::And);
-                        chars.next();
-                    } else {
-                        panic!("Unexpected character {}", c);
-                    }
-                }
+            '}' => {
+                depth -= 1;
+                Token::RBrace
             }
-            '!' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '=' {
-                        tokens.push(Token::BangEqual);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::Bang);
-                    }
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '^' => Token::Xor,
+            '~' => Token::Tilde,
+            '.' if chars.eat('.') => if chars.eat('=') { Token::DotDotEqual } else { Token::DotDot },
+            '.' => Token::Dot,
+            '#' => Token::Hash,
+            '?' if chars.eat('?') => Token::NullCoalesce,
+            '?' if chars.eat('=') => Token::MaybeAssign,
+            '=' => {
+                if chars.eat('=') {
+                    Token::EqualEqual
+                } else if chars.eat('>') {
+                    Token::EqualGreater
+                } else {
+                    Token::Equal
                 }
             }
+            ':' if chars.eat('=') => Token::Assign,
+            ':' if chars.eat(':') => Token::TypeDeclaration,
+            ':' => Token::Colon,
+            '+' if chars.eat('+') => if chars.eat('=') { Token::ConcatAssign } else { Token::Concat },
+            '+' if chars.eat('=') => Token::PlusAssign,
+            '+' => Token::Plus,
+            '&' if chars.eat('&') => Token::And,
+            '&' => Token::Ampersand,
+            '!' => if chars.eat('=') { Token::BangEqual } else { Token::Bang },
             '>' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '=' {
-                        tokens.push(Token::GreaterEqual);
-                        chars.next();
-                    } else if *c == '>' {
-                        tokens.push(Token::ComposeRight);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::Greater);
-                    }
+                if chars.eat('=') {
+                    Token::GreaterEqual
+                } else if chars.eat('>') {
+                    Token::ShiftRight
+                } else {
+                    Token::Greater
                 }
             }
             '<' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '=' {
-                        tokens.push(Token::LessEqual);
-                        chars.next();
-                    } else if *c == '-' {
-                        tokens.push(Token::Bind);
-                        chars.next();
-                    } else if *c == '<' {
-                        tokens.push(Token::ComposeLeft);
-                        chars.next();
-                    } else {
-                        tokens.push(Token::Less);
-                    }
+                if chars.eat('=') {
+                    Token::LessEqual
+                } else if chars.eat('<') {
+                    Token::ShiftLeft
+                } else {
+                    Token::Less
                 }
             }
-            '|' => {
-                if let Some(c) = chars.peek() {
-                    if *c == '>' {
-                        tokens.push(Token::PipeForward);
-                        chars.next();
-                    } else if *c == '|' {
-                        tokens.push(Token::Or);
-                        chars.next();
-                    } else {
-                        panic!("Unexpected character {}", c);
-                    }
+            '|' if chars.eat('>') => Token::PipeForward,
+            '|' if chars.eat('|') => Token::Or,
+            '|' => Token::Bar,
+            '0'..='9' => match number(c, chars, start) {
+                Ok(token) => token,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
                 }
-            }
-            '0'..='9' => {
-                let mut number = c.to_string();
-                while let Some(c) = chars.peek() {
-                    if c.is_numeric() {
-                        number.push(*c);
-                        chars.next();
-                    } else {
-                        break;
-                    }
-                }
-                match number.parse::<f64>() {
-                    Ok(n) => tokens.push(Token::Number(n)),
-                    Err(_) => panic!("Invalid number found: {}", number),
-                }
-            }
+            },
             'a'..='z' | 'A'..='Z' | '_' => {
                 let mut ident = c.to_string();
                 while let Some(c) = chars.peek() {
@@ -289,65 +483,354 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         break;
                     }
                 }
-                match ident.as_str() {
-                    "const" => tokens.push(Token::ConstVar),
-                    "mutate" => tokens.push(Token::Mutate),
-                    "whether" => tokens.push(Token::Whether),
-                    "otherwise" => tokens.push(Token::Otherwise),
-                    "compare" => tokens.push(Token::Compare),
-                    "fn" => tokens.push(Token::Fn),
-                    "foreach" => tokens.push(Token::Foreach),
-                    "forever" => tokens.push(Token::Forever),
-                    "return" => tokens.push(Token::Return),
-                    "break" => tokens.push(Token::Break),
-                    "continue" => tokens.push(Token::Continue),
-                    "change" => tokens.push(Token::Change),
-                    "import" => tokens.push(Token::Import),
-                    "export" => tokens.push(Token::Export),
-                    "nullify" => tokens.push(Token::Nullify),
-                    "print" => tokens.push(Token::Print),
-                    "true" => tokens.push(Token::Boolean(true)),
-                    "false" => tokens.push(Token::Boolean(false)),
-                    "map" => tokens.push(Token::Map),
-                    "filter" => tokens.push(Token::Filter),
-                    "reduce" => tokens.push(Token::Reduce),
-                    "fold" => tokens.push(Token::Fold),
-                    "zip" => tokens.push(Token::Zip),
-                    "lazy" => tokens.push(Token::Lazy),
-                    "memoize" => tokens.push(Token::Memoize),
-                    "lambda" => tokens.push(Token::Lambda),
-                    "compose" => tokens.push(Token::Compose),
-                    "pipe" => tokens.push(Token::Pipe),
-                    "partial" => tokens.push(Token::Partial),
-                    "letRec" => tokens.push(Token::LetRec),
-                    "match" => tokens.push(Token::Match),
-                    "await" => tokens.push(Token::Await),
-                    "yield" => tokens.push(Token::Yield),
-                    "defer" => tokens.push(Token::Defer),
-                    "catch" => tokens.push(Token::Catch),
-                    _ => tokens.push(Token::Ident(ident)),
-                }
+                keyword(&ident).unwrap_or(Token::Ident(ident))
             }
-            '"' => {
-                let mut string = String::new();
-                while let Some(c) = chars.peek() {
-                    if *c == '"' {
-                        chars.next();
-                        break;
-                    } else {
-                        string.push(*c);
-                        chars.next();
-                    }
+            '"' => match string(chars, errors, start) {
+                Ok(token) => token,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
                 }
-                tokens.push(Token::String(string));
-            }
+            },
             ' ' | '\t' | '\n' | '\r' => {
                 continue;
             }
-            _ => panic!("Unexpected character: {}", c),
+            _ => {
+                errors.push(LexError::UnexpectedCharacter { character: c, span: chars.span_from(start) });
+                continue;
+            }
+        };
+
+        let leading_trivia = std::mem::take(&mut trivia);
+        tokens.push(SpannedToken { token, span: chars.span_from(start), leading_trivia });
+    }
+
+    tokens.push(SpannedToken { token: Token::EOF, span: chars.mark(), leading_trivia: trivia });
+    (tokens, false)
+}
+
+/// Reads a string literal whose opening `"` has already been consumed.
+///
+/// A string with `${...}` interpolations becomes a `Token::Template`. Bad
+/// escapes are reported to `errors` without abandoning the string.
+fn string(chars: &mut Cursor, errors: &mut Vec<LexError>, start: Span) -> Result<Token, LexError> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+
+    loop {
+        let escape_start = chars.mark();
+        match chars.next() {
+            None => return Err(LexError::UnterminatedString { span: chars.span_from(start) }),
+            Some('"') => break,
+            Some('\\') => match escape(chars) {
+                Ok(c) => text.push(c),
+                Err(sequence) => {
+                    errors.push(LexError::InvalidEscape { sequence, span: chars.span_from(escape_start) });
+                }
+            },
+            Some('$') if chars.eat('{') => {
+                if !text.is_empty() {
+                    parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                }
+                let (tokens, closed) = lex_tokens(chars, errors, true);
+                if !closed {
+                    return Err(LexError::UnterminatedString { span: chars.span_from(start) });
+                }
+                parts.push(TemplatePart::Code(tokens));
+            }
+            Some(c) => text.push(c),
+        }
+    }
+
+    if parts.is_empty() {
+        return Ok(Token::String(text));
+    }
+    if !text.is_empty() {
+        parts.push(TemplatePart::Text(text));
+    }
+    Ok(Token::Template(parts))
+}
+
+/// Reads an escape sequence whose `\\` has already been consumed. On failure
+/// returns the sequence as written.
+fn escape(chars: &mut Cursor) -> Result<char, String> {
+    let c = match chars.next() {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('r') => '\r',
+        Some('0') => '\0',
+        Some('\\') => '\\',
+        Some('"') => '"',
+        Some('$') => '$',
+        Some('u') => {
+            let mut sequence = String::from("\\u");
+            if !chars.eat('{') {
+                return Err(sequence);
+            }
+            sequence.push('{');
+            let mut hex = String::new();
+            while let Some(c) = chars.peek().copied().filter(char::is_ascii_hexdigit) {
+                hex.push(c);
+                sequence.push(c);
+                chars.next();
+            }
+            if !chars.eat('}') {
+                return Err(sequence);
+            }
+            sequence.push('}');
+            return u32::from_str_radix(&hex, 16)
+                .ok()
+                .filter(|_| hex.len() <= 6)
+                .and_then(char::from_u32)
+                .ok_or(sequence);
+        }
+        Some(c) => return Err(format!("\\{}", c)),
+        None => return Err("\\".to_string()),
+    };
+    Ok(c)
+}
+
+/// Reads a numeric literal whose first digit, `first`, has already been consumed.
+///
+/// Integers may be written in decimal or with a `0x`, `0b` or `0o` prefix;
+/// a fraction or exponent makes the literal a float. `_` may separate digits.
+fn number(first: char, chars: &mut Cursor, start: Span) -> Result<Token, LexError> {
+    let mut literal = first.to_string();
+
+    let radix = match (first, chars.peek()) {
+        ('0', Some('x' | 'X')) => 16,
+        ('0', Some('b' | 'B')) => 2,
+        ('0', Some('o' | 'O')) => 8,
+        _ => 10,
+    };
+
+    let mut is_valid = true;
+    let mut is_float = false;
+    if radix != 10 {
+        literal.extend(chars.next());
+        is_valid &= digits(chars, &mut literal, radix, "");
+    } else {
+        is_valid &= digits(chars, &mut literal, 10, &first.to_string());
+
+        // Only a digit after the dot makes a fraction, so `0..10` stays a range.
+        if chars.peek() == Some(&'.') && chars.peek_second().is_some_and(|c| c.is_ascii_digit()) {
+            is_float = true;
+            literal.extend(chars.next());
+            is_valid &= digits(chars, &mut literal, 10, "");
+        }
+
+        if let Some('e' | 'E') = chars.peek() {
+            is_float = true;
+            literal.extend(chars.next());
+            if let Some('+' | '-') = chars.peek() {
+                literal.extend(chars.next());
+            }
+            is_valid &= digits(chars, &mut literal, 10, "");
+        }
+    }
+
+    // Letters glued to a number (`12ab`, `0xfg`) are part of a bad literal, not a new identifier.
+    while let Some(c) = chars.peek() {
+        if c.is_alphanumeric() || *c == '_' {
+            is_valid = false;
+            literal.push(*c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+
+    let span = chars.span_from(start);
+    if !is_valid {
+        return Err(LexError::InvalidNumber { literal, span });
+    }
+
+    let cleaned: String = literal.chars().skip(if radix == 10 { 0 } else { 2 }).filter(|c| *c != '_').collect();
+    if is_float {
+        match cleaned.parse::<f64>() {
+            Ok(n) => Ok(Token::Number(n)),
+            Err(_) => Err(LexError::InvalidNumber { literal, span }),
+        }
+    } else if radix == 10 {
+        match cleaned.parse::<i64>() {
+            Ok(n) => Ok(Token::Integer(n)),
+            Err(_) => Err(LexError::NumberOutOfRange { literal, span }),
+        }
+    } else {
+        // Hex, binary and octal spell out bits, so all 64 of them are
+        // available: `0xffffffffffffffff` is -1.
+        match u64::from_str_radix(&cleaned, radix) {
+            Ok(n) => Ok(Token::Integer(n as i64)),
+            Err(_) => Err(LexError::NumberOutOfRange { literal, span }),
+        }
+    }
+}
+
+/// Consumes a run of digits in the given radix, with `_` separators, into
+/// `literal`. `already_read` holds digits of the run consumed by the caller.
+///
+/// Returns whether the run is well formed: non-empty, and neither starting
+/// nor ending with `_`.
+fn digits(chars: &mut Cursor, literal: &mut String, radix: u32, already_read: &str) -> bool {
+    let mut run = already_read.to_string();
+    while let Some(c) = chars.peek() {
+        if c.is_digit(radix) || *c == '_' {
+            run.push(*c);
+            literal.push(*c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    !run.is_empty() && !run.starts_with('_') && !run.ends_with('_')
+}
+
+/// Reads the rest of a block comment whose `/*` has already been consumed,
+/// returning its text. Block comments nest. Returns `None` if the input ends
+/// before the comment is closed.
+fn block_comment(chars: &mut Cursor) -> Option<String> {
+    let mut text = String::new();
+    let mut depth = 1;
+
+    while let Some(c) = chars.next() {
+        if c == '/' && chars.eat('*') {
+            depth += 1;
+            text.push_str("/*");
+        } else if c == '*' && chars.eat('/') {
+            depth -= 1;
+            if depth == 0 {
+                return Some(text);
+            }
+            text.push_str("*/");
+        } else {
+            text.push(c);
+        }
+    }
+
+    None
+}
+
+/// The keyword token spelled `ident`, if it is one.
+pub fn keyword(ident: &str) -> Option<Token> {
+    let token = match ident {
+        "const" => Token::ConstVar,
+        "mutate" => Token::Mutate,
+        "whether" => Token::Whether,
+        "otherwise" => Token::Otherwise,
+        "compare" => Token::Compare,
+        "case" => Token::Case,
+        "fn" => Token::Fn,
+        "foreach" => Token::Foreach,
+        "in" => Token::In,
+        "forever" => Token::Forever,
+        "return" => Token::Return,
+        "break" => Token::Break,
+        "continue" => Token::Continue,
+        "change" => Token::Change,
+        "import" => Token::Import,
+        "export" => Token::Export,
+        "nullify" => Token::Nullify,
+        "print" => Token::Print,
+        "true" => Token::Boolean(true),
+        "false" => Token::Boolean(false),
+        "null" => Token::Null,
+        "map" => Token::Map,
+        "filter" => Token::Filter,
+        "reduce" => Token::Reduce,
+        "fold" => Token::Fold,
+        "zip" => Token::Zip,
+        "lazy" => Token::Lazy,
+        "memoize" => Token::Memoize,
+        "lambda" => Token::Lambda,
+        "compose" => Token::Compose,
+        "pipe" => Token::Pipe,
+        "partial" => Token::Partial,
+        "letRec" => Token::LetRec,
+        "match" => Token::Match,
+        "await" => Token::Await,
+        "yield" => Token::Yield,
+        "defer" => Token::Defer,
+        "catch" => Token::Catch,
+        _ => return None,
+    };
+    Some(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Result<Vec<Token>, Vec<LexError>> {
+        tokenize(source).map(|tokens| tokens.into_iter().map(|t| t.token).filter(|t| *t != Token::EOF).collect())
+    }
+
+    #[test]
+    fn integers_and_floats() {
+        assert_eq!(lex("42 1_000_000"), Ok(vec![Token::Integer(42), Token::Integer(1_000_000)]));
+        assert_eq!(lex("3.25 1e3 2.5E-1"), Ok(vec![Token::Number(3.25), Token::Number(1e3), Token::Number(0.25)]));
+    }
+
+    #[test]
+    fn dot_without_a_digit_after_it_is_a_range() {
+        assert_eq!(lex("0..10"), Ok(vec![Token::Integer(0), Token::DotDot, Token::Integer(10)]));
+    }
+
+    #[test]
+    fn radix_literals_use_all_64_bits() {
+        assert_eq!(lex("0xff 0b1010 0o17"), Ok(vec![Token::Integer(255), Token::Integer(10), Token::Integer(15)]));
+        assert_eq!(lex("0xffff_ffff_ffff_ffff"), Ok(vec![Token::Integer(-1)]));
+        assert!(matches!(lex("0x1_0000_0000_0000_0000").unwrap_err()[..], [LexError::NumberOutOfRange { .. }]));
+    }
+
+    #[test]
+    fn decimal_integers_past_i64_are_out_of_range() {
+        assert!(matches!(lex("9223372036854775808").unwrap_err()[..], [LexError::NumberOutOfRange { .. }]));
+    }
+
+    #[test]
+    fn malformed_literals_are_one_error_each() {
+        for source in ["12ab", "1_", "0x", "0b2", "1e"] {
+            match lex(source) {
+                Err(errors) => assert!(matches!(errors[..], [LexError::InvalidNumber { .. }]), "{}: {:?}", source, errors),
+                Ok(tokens) => panic!("{} lexed as {:?}", source, tokens),
+            }
         }
     }
 
-    tokens.push(Token::EOF);
-    tokens
+    fn trivia(source: &str) -> Vec<(TriviaKind, String)> {
+        tokenize(source)
+            .expect("source should lex")
+            .into_iter()
+            .flat_map(|t| t.leading_trivia)
+            .map(|trivia| (trivia.kind, trivia.text))
+            .collect()
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        assert_eq!(lex("1 /* a /* b */ c */ 2"), Ok(vec![Token::Integer(1), Token::Integer(2)]));
+        assert_eq!(trivia("/* a /* b */ c */ 1"), vec![(TriviaKind::BlockComment, " a /* b */ c ".to_string())]);
+    }
+
+    #[test]
+    fn three_slashes_are_documentation_and_four_are_not() {
+        assert_eq!(trivia("/// docs\n1"), vec![(TriviaKind::DocComment, " docs".to_string())]);
+        assert_eq!(trivia("//// line\n1"), vec![(TriviaKind::LineComment, "/ line".to_string())]);
+        assert_eq!(trivia("// note\n1"), vec![(TriviaKind::LineComment, " note".to_string())]);
+    }
+
+    #[test]
+    fn trailing_comments_attach_to_eof() {
+        let tokens = tokenize("1\n// the end").unwrap();
+        let eof = tokens.last().unwrap();
+        assert_eq!(eof.token, Token::EOF);
+        assert_eq!(eof.leading_trivia.len(), 1);
+        assert_eq!(eof.leading_trivia[0].text, " the end");
+        assert!(tokens[0].leading_trivia.is_empty());
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        assert!(matches!(lex("1 /* /* */").unwrap_err()[..], [LexError::UnterminatedComment { .. }]));
+    }
 }
@@ -0,0 +1,8 @@
+pub mod span;
+pub mod diagnostic;
+pub mod environment;
+pub mod lexer;
+pub mod parser;
+pub mod interpreter;
+pub mod builtins;
+pub mod cache;
@@ -1,19 +1,179 @@
-mod lexer;
-mod parser;
-mod interpreter;
+mod repl;
 
-use interpreter::Interpreter;
-use lexer::tokenize;
-use parser::Parser;
+use std::env;
+use std::fs;
+use std::io::{ self, IsTerminal, Read };
+use std::process;
+use std::thread;
+
+use berry_lang::diagnostic::{ Diagnostic, RenderMode, Renderer };
+use berry_lang::interpreter::Interpreter;
+use berry_lang::lexer::tokenize;
+use berry_lang::parser::Parser;
+
+const USAGE: &str = "\
+Usage:
+    berry [repl] [args...]        Start the interactive REPL
+    berry <file.crb> [args...]    Run a script
+    berry - [args...]             Run a script read from stdin
+    berry -e <code> [args...]     Run a one-liner
+
+Options:
+    -e <code>                    Evaluate <code> instead of reading a file
+    --diagnostics <mode>         Error output: color, plain or json
+                                 (default: color on a terminal, plain otherwise)
+    -h, --help                   Print this help";
+
+/// Where the program text comes from.
+enum Source {
+    File(String),
+    Stdin,
+    Inline(String),
+}
+
+/// The interpreter grows its own stack for deep recursion in a script, but
+/// parsing deeply nested code and dropping deeply nested values don't, so
+/// give them far more room than the default main thread.
+const STACK_SIZE: usize = 1024 * 1024 * 1024;
 
 fn main() {
-    let input = r#"print(100/3)"#;
+    let runner = thread::Builder::new()
+        .name("berry".to_string())
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .expect("failed to start the interpreter thread");
+
+    if runner.join().is_err() {
+        process::exit(101);
+    }
+}
+
+fn run() {
+    let mut args = env::args().skip(1).peekable();
+
+    let mut mode = default_render_mode();
+    while let Some(arg) = args.peek() {
+        let value = if arg == "--diagnostics" {
+            args.next();
+            args.next()
+        } else if let Some(value) = arg.strip_prefix("--diagnostics=") {
+            let value = value.to_string();
+            args.next();
+            Some(value)
+        } else {
+            break;
+        };
+
+        mode = match value.as_deref() {
+            Some("color") => RenderMode::Color,
+            Some("plain") => RenderMode::Plain,
+            Some("json") => RenderMode::Json,
+            Some(other) => usage_error(&format!("unknown diagnostics mode: {}", other)),
+            None => usage_error("--diagnostics expects an argument"),
+        };
+    }
+
+    let source = match args.next().as_deref() {
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return;
+        }
+        Some("-e") => match args.next() {
+            Some(code) => Source::Inline(code),
+            None => usage_error("-e expects an argument"),
+        },
+        Some("-") => Source::Stdin,
+        Some(flag) if flag.starts_with('-') => usage_error(&format!("unknown option: {}", flag)),
+        Some("repl") | None => {
+            if let Err(err) = repl::run(args.collect(), mode) {
+                eprintln!("error: {}", err);
+                process::exit(2);
+            }
+            return;
+        }
+        Some(path) => Source::File(path.to_string()),
+    };
+
+    let input = match read_source(&source) {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(2);
+        }
+    };
+
+    let name = source.name();
+    let renderer = Renderer::new(name, &input, mode);
+
+    let tokens = match tokenize(&input) {
+        Ok(tokens) => tokens,
+        Err(errors) => {
+            report(&renderer, errors.iter().map(Diagnostic::from));
+            process::exit(1);
+        }
+    };
 
-    let tokens = tokenize(input);
     let mut parser = Parser::new(tokens);
+    let parsed = parser.parse();
+    report(&renderer, parser.warnings().iter().map(Diagnostic::from));
+    let ast = match parsed {
+        Ok(ast) => ast,
+        Err(errors) => {
+            report(&renderer, errors.iter().map(Diagnostic::from));
+            process::exit(1);
+        }
+    };
+
+    let mut interpreter = Interpreter::with_args(args.collect());
+    if let Err(err) = interpreter.interpret(&ast) {
+        report(&renderer, [Diagnostic::from(&err)]);
+        process::exit(1);
+    }
+}
+
+fn report(renderer: &Renderer, diagnostics: impl IntoIterator<Item = Diagnostic>) {
+    for diagnostic in diagnostics {
+        eprint!("{}", renderer.render(&diagnostic));
+    }
+}
+
+/// Colors are only used when stderr is a terminal and `NO_COLOR` is unset.
+fn default_render_mode() -> RenderMode {
+    if io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none() {
+        RenderMode::Color
+    } else {
+        RenderMode::Plain
+    }
+}
 
-    let ast = parser.parse();
-    let mut interpreter = Interpreter::new();
+impl Source {
+    /// How the source is referred to in error messages.
+    fn name(&self) -> &str {
+        match self {
+            Source::File(path) => path,
+            Source::Stdin => "<stdin>",
+            Source::Inline(_) => "<inline>",
+        }
+    }
+}
+
+fn read_source(source: &Source) -> io::Result<String> {
+    match source {
+        Source::File(path) => {
+            fs::read_to_string(path).map_err(|err| {
+                io::Error::new(err.kind(), format!("cannot read {}: {}", path, err))
+            })
+        }
+        Source::Stdin => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            Ok(input)
+        }
+        Source::Inline(code) => Ok(code.clone()),
+    }
+}
 
-    println!("{:?}", interpreter.interpret(&ast));
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    process::exit(2);
 }
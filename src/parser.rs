@@ -1,14 +1,129 @@
-use crate::lexer::Token;
+use std::error::Error;
+use std::fmt;
+
+use crate::lexer::{ keyword, SpannedToken, TemplatePart, Token };
+use crate::span::Span;
+
+/// How close to the end of the stack parsing may get before growing it, and
+/// how much to add each time. Statements, unary operands and patterns are
+/// where every level of nesting passes through.
+const STACK_RED_ZONE: usize = 128 * 1024;
+const STACK_GROWTH: usize = 2 * 1024 * 1024;
+
+/// A syntax tree node and the source it was parsed from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
-pub enum Expr {
+pub enum ExprKind {
     Number(f64),
+    Integer(i64),
+    Boolean(bool),
+    Null,
+    String(String),
+    /// An interpolated string: the pieces are evaluated and joined in order.
+    Interpolation(Vec<Expr>),
+    Unary(UnaryOp, Box<Expr>),
     Binary(Box<Expr>, BinOp, Box<Expr>),
+    /// `[a, b, c]`.
+    List(Vec<Expr>),
+    /// `#{ key: value, "quoted key": value }`.
+    Map(Vec<(String, Expr)>),
+    /// `target[index]`. A range index takes a slice.
+    Index { target: Box<Expr>, index: Box<Expr> },
+    /// `target.name`, shorthand for `target["name"]` on maps.
+    Field { target: Box<Expr>, name: String },
     Var(String),
-    Assign(String, Box<Expr>),
+    /// `target = value`, where the target is a variable or an index or field
+    /// of one.
+    Assign(Box<Expr>, Box<Expr>),
+    /// `target += value` and friends: `target = target op value`, with the
+    /// target's indexes evaluated once.
+    CompoundAssign(Box<Expr>, BinOp, Box<Expr>),
+    /// `target ?= value`: assigns only when the target is null.
+    MaybeAssign(Box<Expr>, Box<Expr>),
+    /// `nullify target`, which takes the same targets as `=`.
+    Nullify(Box<Expr>),
     VarDecl(bool, String, Box<Expr>),
     PrintStmt(Box<Expr>),
     Block(Vec<Expr>),
+    /// `whether`/`otherwise`. `else_branch` is a block or, for
+    /// `otherwise whether`, another `If`.
+    If { condition: Box<Expr>, then_branch: Box<Expr>, else_branch: Option<Box<Expr>> },
+    FnDecl { name: String, params: Vec<String>, body: Box<Expr> },
+    /// `memoize fn ...` or `memoize(capacity) fn ...`, where `function` is a `FnDecl`.
+    Memoize { capacity: Option<Box<Expr>>, function: Box<Expr> },
+    /// `lambda(a, b) -> body` or `(a, b) -> body`.
+    Lambda { params: Vec<String>, body: Box<Expr> },
+    Call { callee: Box<Expr>, args: Vec<Expr> },
+    Return(Option<Box<Expr>>),
+    Forever { label: Option<String>, body: Box<Expr> },
+    /// `foreach item in iterable` or `foreach key, item in iterable`.
+    Foreach { label: Option<String>, key: Option<String>, item: String, iterable: Box<Expr>, body: Box<Expr> },
+    Break(Option<String>),
+    Continue(Option<String>),
+    /// `match subject { pattern => body, ... }`. The first arm whose pattern
+    /// and guard both match is evaluated.
+    Match { subject: Box<Expr>, arms: Vec<MatchArm> },
+    /// `compare subject { case a: ... default: ... }`. Execution starts at
+    /// the first case equal to `subject`, or at `default`, and falls through
+    /// the cases after it until a `break`.
+    Compare { subject: Box<Expr>, cases: Vec<CompareCase> },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompareCase {
+    /// `None` for `default`.
+    pub value: Option<Expr>,
+    pub body: Vec<Expr>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    /// The `whether` condition after the pattern, if any.
+    pub guard: Option<Expr>,
+    pub body: Expr,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+    /// `_` or `default`.
+    Wildcard,
+    /// A name, bound to whatever value is matched.
+    Binding(String),
+    /// A number, string, boolean or null literal, compared with `==`.
+    Literal(Box<Expr>),
+    /// `start..end` or `start..=end`, with literal bounds.
+    Range { start: Box<Expr>, end: Box<Expr>, inclusive: bool },
+    /// `[a, b, ..rest, z]`. `rest` is `Some` when there is a `..`, which
+    /// may or may not name the elements it skips.
+    List { before: Vec<Pattern>, rest: Option<Option<String>>, after: Vec<Pattern> },
+    /// `#{ key: pattern, other }`. A bare key binds the field to a variable
+    /// of the same name. Keys that are not mentioned are ignored.
+    Map(Vec<(String, Pattern)>),
+    /// `a | b | c`.
+    Or(Vec<Pattern>),
+}
+
+impl Pattern {
+    /// Whether the pattern matches every possible value.
+    pub fn is_irrefutable(&self) -> bool {
+        match self {
+            Pattern::Wildcard | Pattern::Binding(_) => true,
+            Pattern::Or(alternatives) => alternatives.iter().any(Pattern::is_irrefutable),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -18,162 +133,1299 @@ pub enum BinOp {
     Multiply,
     Divide,
     Mod,
+    Power,
+    Concat,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    And,
+    Or,
+    /// `??`: the left side unless it is null, in which case the right side.
+    NullCoalesce,
+    Range,
+    RangeInclusive,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    BitNot,
+}
+
+/// The operator a token stands for in binary position, with its precedence.
+/// Higher numbers bind tighter. `**` is missing because it binds tighter
+/// than unary operators and is handled by `parse_power`.
+fn binary_operator(token: &Token) -> Option<(BinOp, u8)> {
+    let operator = match token {
+        Token::DotDot => (BinOp::Range, 2),
+        Token::DotDotEqual => (BinOp::RangeInclusive, 2),
+        Token::NullCoalesce => (BinOp::NullCoalesce, 3),
+        Token::Or => (BinOp::Or, 4),
+        Token::And => (BinOp::And, 5),
+        Token::EqualEqual => (BinOp::Equal, 6),
+        Token::BangEqual => (BinOp::NotEqual, 6),
+        Token::Greater => (BinOp::Greater, 7),
+        Token::GreaterEqual => (BinOp::GreaterEqual, 7),
+        Token::Less => (BinOp::Less, 7),
+        Token::LessEqual => (BinOp::LessEqual, 7),
+        Token::Bar => (BinOp::BitOr, 9),
+        Token::Xor => (BinOp::BitXor, 10),
+        Token::Ampersand => (BinOp::BitAnd, 11),
+        // Shifts on numbers, composition on functions.
+        Token::ShiftLeft => (BinOp::ShiftLeft, 12),
+        Token::ShiftRight => (BinOp::ShiftRight, 12),
+        Token::Plus => (BinOp::Plus, 13),
+        Token::Minus => (BinOp::Minus, 13),
+        Token::Concat => (BinOp::Concat, 13),
+        Token::Asterisk => (BinOp::Multiply, 14),
+        Token::Slash => (BinOp::Divide, 14),
+        Token::Percent => (BinOp::Mod, 14),
+        _ => return None,
+    };
+    Some(operator)
+}
+
+/// `x |> f(a)` is `f(x, a)`. It binds loosest of all, so a whole
+/// calculation can be piped along.
+const PIPE_PRECEDENCE: u8 = 1;
+
+/// ``a `f` b`` is `f(a, b)`. It sits between comparisons and arithmetic.
+const INFIX_CALL_PRECEDENCE: u8 = 8;
+
+/// Whether `expr` can be assigned to: a variable, or an index or field of
+/// something that can.
+fn is_assignable(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Var(_) => true,
+        ExprKind::Index { target, .. } | ExprKind::Field { target, .. } => is_assignable(target),
+        _ => false,
+    }
+}
+
+/// The operator a compound assignment token such as `+=` applies.
+fn compound_operator(token: &Token) -> Option<BinOp> {
+    let operator = match token {
+        Token::PlusAssign => BinOp::Plus,
+        Token::MinusAssign => BinOp::Minus,
+        Token::MulAssign => BinOp::Multiply,
+        Token::DivAssign => BinOp::Divide,
+        Token::ModAssign => BinOp::Mod,
+        Token::PowAssign => BinOp::Power,
+        Token::ConcatAssign => BinOp::Concat,
+        _ => return None,
+    };
+    Some(operator)
+}
+
+/// Whether `token` can follow a name to start an assignment or declaration.
+fn is_assignment_operator(token: &Token) -> bool {
+    matches!(token, Token::Equal | Token::Assign | Token::MaybeAssign) || compound_operator(token).is_some()
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            BinOp::Plus => "+",
+            BinOp::Minus => "-",
+            BinOp::Multiply => "*",
+            BinOp::Divide => "/",
+            BinOp::Mod => "%",
+            BinOp::Power => "**",
+            BinOp::Concat => "++",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+            BinOp::ShiftLeft => "<<",
+            BinOp::ShiftRight => ">>",
+            BinOp::Equal => "==",
+            BinOp::NotEqual => "!=",
+            BinOp::Greater => ">",
+            BinOp::GreaterEqual => ">=",
+            BinOp::Less => "<",
+            BinOp::LessEqual => "<=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+            BinOp::NullCoalesce => "??",
+            BinOp::Range => "..",
+            BinOp::RangeInclusive => "..=",
+        };
+        f.write_str(symbol)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseError {
+    /// `found` showed up where `expected` (a description such as `"')' after arguments"`) should have been.
+    UnexpectedToken { found: Token, expected: String, span: Span },
+    UnexpectedEof { expected: String, span: Span },
+    InvalidAssignmentTarget { span: Span },
+    ReturnOutsideFunction { span: Span },
+    /// `keyword` is `break` or `continue`.
+    OutsideLoop { keyword: Token, span: Span },
+    UnknownLabel { label: String, span: Span },
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedToken { span, .. }
+            | ParseError::UnexpectedEof { span, .. }
+            | ParseError::InvalidAssignmentTarget { span }
+            | ParseError::ReturnOutsideFunction { span }
+            | ParseError::OutsideLoop { span, .. }
+            | ParseError::UnknownLabel { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken { found, expected, .. } => {
+                write!(f, "Expected {}, found `{}`", expected, found)
+            }
+            ParseError::UnexpectedEof { expected, .. } => {
+                write!(f, "Unexpected end of input, expected {}", expected)
+            }
+            ParseError::InvalidAssignmentTarget { .. } => write!(f, "Invalid assignment target"),
+            ParseError::ReturnOutsideFunction { .. } => write!(f, "`return` outside of a function"),
+            ParseError::OutsideLoop { keyword, .. } => write!(f, "`{}` outside of a loop", keyword),
+            ParseError::UnknownLabel { label, .. } => write!(f, "No enclosing loop is labeled `{}`", label),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Code that is valid but probably not what was meant.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseWarning {
+    /// A `match` without an arm that catches every value.
+    NonExhaustiveMatch { span: Span },
+}
+
+impl ParseWarning {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseWarning::NonExhaustiveMatch { span } => *span,
+        }
+    }
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseWarning::NonExhaustiveMatch { .. } => write!(f, "`match` has no default arm"),
+        }
+    }
 }
 
 pub struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<SpannedToken>,
     pos: usize,
+    /// Span of the most recently consumed token.
+    previous_span: Span,
+    errors: Vec<ParseError>,
+    warnings: Vec<ParseWarning>,
+    /// How many function bodies enclose the current position.
+    function_depth: usize,
+    /// Labels of the loops enclosing the current position within the
+    /// current function, innermost last. Unlabeled loops are `None`.
+    loops: Vec<Option<String>>,
+    /// How many `compare` bodies enclose the current position within the
+    /// current function. A plain `break` is allowed inside them.
+    compares: usize,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, pos: 0 }
+    pub fn new(tokens: Vec<SpannedToken>) -> Self {
+        Parser { tokens, pos: 0, previous_span: Span::default(), errors: Vec::new(), warnings: Vec::new(), function_depth: 0, loops: Vec::new(), compares: 0 }
     }
 
-    pub fn parse(&mut self) -> Expr {
-        self.parse_block()
+    /// Parses the whole token stream.
+    ///
+    /// A statement that fails to parse is skipped up to the next `;` or `}`
+    /// and parsing carries on, so every syntax error is reported at once.
+    pub fn parse(&mut self) -> Result<Expr, Vec<ParseError>> {
+        let program = self.parse_block(false);
+        if self.errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    /// Warnings found so far. They are kept whether or not parsing succeeded.
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
     }
 
     fn current_token(&self) -> Token {
-        if self.pos >= self.tokens.len() { Token::EOF } else { self.tokens[self.pos].clone() }
+        self.peek_token(0)
+    }
+
+    /// The token `offset` places after the current one.
+    fn peek_token(&self, offset: usize) -> Token {
+        match self.tokens.get(self.pos + offset) {
+            Some(token) => token.token.clone(),
+            None => Token::EOF,
+        }
+    }
+
+    /// The current token as a field name or map key. Keywords are allowed
+    /// there, so `config.map` works.
+    fn current_name(&self) -> Option<String> {
+        match self.current_token() {
+            Token::Ident(name) => Some(name),
+            token => {
+                let text = token.to_string();
+                (keyword(&text) == Some(token)).then_some(text)
+            }
+        }
+    }
+
+    fn current_span(&self) -> Span {
+        match self.tokens.get(self.pos).or(self.tokens.last()) {
+            Some(token) => token.span,
+            None => Span::default(),
+        }
+    }
+
+    /// Span from `start` up to the end of the last consumed token.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.previous_span)
     }
 
     fn advance(&mut self) {
         if self.pos < self.tokens.len() {
+            self.previous_span = self.tokens[self.pos].span;
             self.pos += 1;
         }
     }
 
-    fn parse_block(&mut self) -> Expr {
+    /// An error for the current token, which is not what `expected` describes.
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match self.current_token() {
+            Token::EOF => {
+                // Point just past the last real token rather than at trailing whitespace.
+                let last = self.previous_span;
+                let span = Span::new(last.end, last.end, last.line.max(1), last.column + last.len());
+                ParseError::UnexpectedEof { expected: expected.to_string(), span }
+            }
+            found => ParseError::UnexpectedToken { found, expected: expected.to_string(), span: self.current_span() },
+        }
+    }
+
+    /// Consumes the current token if it is `token`, otherwise reports `expected`.
+    fn expect(&mut self, token: Token, expected: &str) -> Result<(), ParseError> {
+        if self.current_token() == token {
+            self.advance();
+            Ok(())
+        } else {
+            Err(self.unexpected(expected))
+        }
+    }
+
+    /// Skips ahead to a point where parsing can safely resume: just past the
+    /// next `;`, or at the next `}`.
+    fn synchronize(&mut self) {
+        loop {
+            match self.current_token() {
+                Token::EOF | Token::RBrace => return,
+                Token::Semicolon => {
+                    self.advance();
+                    return;
+                }
+                _ => self.advance(),
+            }
+        }
+    }
+
+    /// Parses statements up to the end of input, or up to (not including)
+    /// the closing `}` when `nested`.
+    fn parse_block(&mut self, nested: bool) -> Expr {
+        let start = self.current_span();
         let mut statements = Vec::new();
 
-        while self.current_token() != Token::EOF {
-            let stmt = self.parse_statement();
-            statements.push(stmt);
+        while self.current_token() != Token::EOF && !(nested && self.current_token() == Token::RBrace) {
+            let pos = self.pos;
+            match self.parse_statement() {
+                Ok(stmt) => statements.push(stmt),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize();
+                    // Always make progress, even when the error sits on the token we stop at.
+                    if self.pos == pos {
+                        self.advance();
+                    }
+                    continue;
+                }
+            }
 
             if self.current_token() == Token::Semicolon {
                 self.advance();
             }
         }
 
-        Expr::Block(statements)
+        Expr::new(ExprKind::Block(statements), self.span_from(start))
     }
 
-    fn parse_statement(&mut self) -> Expr {
-        match self.current_token() {
-            Token::ConstVar => self.parse_var_declaration(true),
-            Token::Mutate => self.parse_var_declaration(false),
-            Token::Print => self.parse_print(),
-            _ => self.parse_assignment(),
-        }
+    /// Parses `{ statements }`.
+    fn parse_braced_block(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.expect(Token::LBrace, "'{' to start a block")?;
+        let mut block = self.parse_block(true);
+        self.expect(Token::RBrace, "'}' to close the block")?;
+        block.span = self.span_from(start);
+        Ok(block)
     }
 
-    fn parse_print(&mut self) -> Expr {
+    fn parse_statement(&mut self) -> Result<Expr, ParseError> {
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || {
+            match self.current_token() {
+                Token::ConstVar => self.parse_var_declaration(true),
+                Token::Mutate => self.parse_var_declaration(false),
+                Token::Print => self.parse_print(),
+                Token::Fn => self.parse_fn_declaration(),
+                Token::Memoize if self.at_memoized_fn() => self.parse_memoized_fn(),
+                Token::Return => self.parse_return(),
+                Token::Forever | Token::Foreach => self.parse_loop(None),
+                Token::Ident(name) if self.peek_token(1) == Token::Assign => self.parse_short_declaration(name),
+                Token::Ident(label) if self.peek_token(1) == Token::Colon => {
+                    let start = self.current_span();
+                    self.advance();
+                    self.advance();
+                    if !matches!(self.current_token(), Token::Forever | Token::Foreach) {
+                        return Err(self.unexpected("'forever' or 'foreach' after a loop label"));
+                    }
+                    let mut looped = self.parse_loop(Some(label))?;
+                    looped.span = self.span_from(start);
+                    Ok(looped)
+                }
+                Token::Break | Token::Continue => self.parse_loop_jump(),
+                Token::Compare => self.parse_compare(),
+                Token::Nullify => self.parse_nullify(),
+                _ => self.parse_assignment(),
+            }
+        })
+    }
+
+    /// Parses `forever { ... }` or `foreach item in iterable { ... }`.
+    fn parse_loop(&mut self, label: Option<String>) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        let is_foreach = self.current_token() == Token::Foreach;
         self.advance();
 
-        if let Token::LeftParen = self.current_token() {
+        let header = if is_foreach { Some(self.parse_foreach_header()?) } else { None };
+
+        self.loops.push(label.clone());
+        let body = self.parse_braced_block();
+        self.loops.pop();
+        let body = Box::new(body?);
+
+        let kind = match header {
+            Some((key, item, iterable)) => ExprKind::Foreach { label, key, item, iterable: Box::new(iterable), body },
+            None => ExprKind::Forever { label, body },
+        };
+        Ok(Expr::new(kind, self.span_from(start)))
+    }
+
+    /// Parses `item in iterable` or `key, item in iterable`.
+    fn parse_foreach_header(&mut self) -> Result<(Option<String>, String, Expr), ParseError> {
+        let Token::Ident(first) = self.current_token() else {
+            return Err(self.unexpected("loop variable after 'foreach'"));
+        };
+        self.advance();
+
+        let (key, item) = if self.current_token() == Token::Comma {
+            self.advance();
+            let Token::Ident(second) = self.current_token() else {
+                return Err(self.unexpected("second loop variable after ','"));
+            };
             self.advance();
-            let to_print = self.parse_expr();
+            (Some(first), second)
+        } else {
+            (None, first)
+        };
+
+        self.expect(Token::In, "'in' after loop variable")?;
+        let iterable = self.parse_expr()?;
+        Ok((key, item, iterable))
+    }
+
+    /// Parses `break` or `continue`, with an optional label.
+    fn parse_loop_jump(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        let keyword = self.current_token();
+        self.advance();
 
-            if let Token::RightParen = self.current_token() {
+        // Semicolons are optional, so a name only counts as a label on the
+        // keyword's own line, and not when it starts an assignment or is
+        // followed by `:`, as in a compare's `default:`.
+        let next = self.peek_token(1);
+        let label = match self.current_token() {
+            Token::Ident(label)
+                if self.current_span().line == start.line && next != Token::Colon && !is_assignment_operator(&next) =>
+            {
                 self.advance();
+                Some(label)
+            }
+            _ => None,
+        };
+
+        let leaves_compare = keyword == Token::Break && label.is_none() && self.compares > 0;
+        if self.loops.is_empty() && !leaves_compare {
+            return Err(ParseError::OutsideLoop { keyword, span: self.span_from(start) });
+        }
+        if let Some(label) = &label {
+            if !self.loops.iter().any(|l| l.as_ref() == Some(label)) {
+                return Err(ParseError::UnknownLabel { label: label.clone(), span: self.span_from(start) });
+            }
+        }
+
+        let kind = if keyword == Token::Break { ExprKind::Break(label) } else { ExprKind::Continue(label) };
+        Ok(Expr::new(kind, self.span_from(start)))
+    }
+
+    /// Parses `compare value { case a: ... case b: ... default: ... }`.
+    fn parse_compare(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+        let subject = self.parse_expr()?;
+        self.expect(Token::LBrace, "'{' after the value to compare")?;
+
+        self.compares += 1;
+        let cases = self.parse_compare_cases();
+        self.compares -= 1;
+        let cases = cases?;
+
+        self.expect(Token::RBrace, "'}' to close the compare")?;
+        Ok(Expr::new(ExprKind::Compare { subject: Box::new(subject), cases }, self.span_from(start)))
+    }
+
+    fn parse_compare_cases(&mut self) -> Result<Vec<CompareCase>, ParseError> {
+        let mut cases = Vec::new();
+        while !matches!(self.current_token(), Token::RBrace | Token::EOF) {
+            let value = match self.current_token() {
+                Token::Case => {
+                    self.advance();
+                    Some(self.parse_expr()?)
+                }
+                Token::Ident(name) if name == "default" => {
+                    self.advance();
+                    None
+                }
+                _ => return Err(self.unexpected("'case' or 'default'")),
+            };
+            self.expect(Token::Colon, "':' after the case")?;
+
+            let mut body = Vec::new();
+            while !self.at_case_boundary() {
+                body.push(self.parse_statement()?);
                 if self.current_token() == Token::Semicolon {
                     self.advance();
                 }
-                Expr::PrintStmt(Box::new(to_print))
-            } else {
-                panic!("Expected ')' after expression in print statement.");
             }
-        } else {
-            panic!("Expected '(' after 'print'.");
+            cases.push(CompareCase { value, body });
+        }
+        Ok(cases)
+    }
+
+    /// Whether the current token ends the statements of a compare case.
+    fn at_case_boundary(&self) -> bool {
+        match self.current_token() {
+            Token::Case | Token::RBrace | Token::EOF => true,
+            Token::Ident(name) => name == "default" && self.peek_token(1) == Token::Colon,
+            _ => false,
         }
     }
 
-    fn parse_var_declaration(&mut self, is_immut: bool) -> Expr {
+    /// Parses `fn name(a, b) { ... }`.
+    fn parse_fn_declaration(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
         self.advance();
-        if let Token::Ident(name) = self.current_token() {
-            let var_name = name.clone();
+
+        let Token::Ident(name) = self.current_token() else {
+            return Err(self.unexpected("function name after 'fn'"));
+        };
+        self.advance();
+
+        self.expect(Token::LeftParen, "'(' after function name")?;
+        let params = self.parse_params()?;
+        let body = self.parse_function_body(Self::parse_braced_block)?;
+
+        Ok(Expr::new(ExprKind::FnDecl { name, params, body: Box::new(body) }, self.span_from(start)))
+    }
+
+    /// Parses `memoize fn name(a) { ... }`, with an optional cache capacity in
+    /// parentheses after `memoize`.
+    fn parse_memoized_fn(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+
+        let capacity = if self.current_token() == Token::LeftParen {
             self.advance();
-            if let Token::Equal = self.current_token() {
-                self.advance();
-                let value = self.parse_expr();
-                Expr::VarDecl(is_immut, var_name, Box::new(value))
-            } else {
-                panic!("Expected '=' after variable name in declaration");
-            }
+            let capacity = self.parse_expr()?;
+            self.expect(Token::RightParen, "')' after the cache capacity")?;
+            Some(Box::new(capacity))
         } else {
-            panic!("Expected identifier after 'const' or 'mutate'");
+            None
+        };
+        let function = self.parse_fn_declaration()?;
+
+        Ok(Expr::new(ExprKind::Memoize { capacity, function: Box::new(function) }, self.span_from(start)))
+    }
+
+    /// Whether the `memoize` at the current position starts a memoized
+    /// function declaration rather than a call to the `memoize` built-in.
+    /// Semicolons are optional, so the `fn` must be on the same line as the
+    /// `memoize` or capacity before it; otherwise it starts a new statement.
+    fn at_memoized_fn(&self) -> bool {
+        let mut offset = 1;
+        if self.peek_token(1) == Token::LeftParen {
+            let mut depth = 0;
+            loop {
+                match self.peek_token(offset) {
+                    Token::LeftParen => depth += 1,
+                    Token::RightParen if depth == 1 => break,
+                    Token::RightParen => depth -= 1,
+                    Token::EOF => return false,
+                    _ => {}
+                }
+                offset += 1;
+            }
+            offset += 1;
+        }
+        let same_line = match (self.tokens.get(self.pos + offset - 1), self.tokens.get(self.pos + offset)) {
+            (Some(before), Some(after)) => before.span.line == after.span.line,
+            _ => false,
+        };
+        same_line && self.peek_token(offset) == Token::Fn
+    }
+
+    /// Parses parameter names up to and including the closing `)`.
+    fn parse_params(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut params = Vec::new();
+        while self.current_token() != Token::RightParen {
+            let Token::Ident(param) = self.current_token() else {
+                return Err(self.unexpected("parameter name"));
+            };
+            self.advance();
+            params.push(param);
+            if self.current_token() != Token::Comma {
+                break;
+            }
+            self.advance();
         }
+        self.expect(Token::RightParen, "')' after parameters")?;
+        Ok(params)
     }
 
-    fn parse_assignment(&mut self) -> Expr {
-        let expr = self.parse_expr();
+    /// Runs `parse` on a function body, where `return` is allowed and the
+    /// enclosing loops and compares are out of reach.
+    fn parse_function_body(&mut self, parse: impl FnOnce(&mut Self) -> Result<Expr, ParseError>) -> Result<Expr, ParseError> {
+        let enclosing_loops = std::mem::take(&mut self.loops);
+        let enclosing_compares = std::mem::take(&mut self.compares);
+        self.function_depth += 1;
+        let body = parse(self);
+        self.function_depth -= 1;
+        self.loops = enclosing_loops;
+        self.compares = enclosing_compares;
+        body
+    }
 
-        if let Token::Equal = self.current_token() {
+    /// Parses `lambda(a, b) -> body` or `(a, b) -> body`, where the body is
+    /// an expression or a braced block.
+    fn parse_lambda(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        if self.current_token() == Token::Lambda {
             self.advance();
-            if let Expr::Var(name) = expr {
-                let value = self.parse_expr();
-                return Expr::Assign(name, Box::new(value));
+        }
+        self.expect(Token::LeftParen, "'(' after 'lambda'")?;
+        let params = self.parse_params()?;
+        self.expect(Token::LambdaArrow, "'->' after the parameters")?;
+
+        let body = self.parse_function_body(|parser| {
+            if parser.current_token() == Token::LBrace {
+                parser.parse_braced_block()
             } else {
-                panic!("Invalid assignment target");
+                parser.parse_expr()
             }
+        })?;
+        Ok(Expr::new(ExprKind::Lambda { params, body: Box::new(body) }, self.span_from(start)))
+    }
+
+    /// Whether the `(` at the current position starts the parameters of a
+    /// `(a, b) -> body` lambda rather than a parenthesized expression.
+    fn at_lambda_params(&self) -> bool {
+        let mut offset = 1;
+        if self.peek_token(offset) != Token::RightParen {
+            loop {
+                if !matches!(self.peek_token(offset), Token::Ident(_)) {
+                    return false;
+                }
+                match self.peek_token(offset + 1) {
+                    Token::Comma => offset += 2,
+                    Token::RightParen => {
+                        offset += 1;
+                        break;
+                    }
+                    _ => return false,
+                }
+            }
+        }
+        self.peek_token(offset + 1) == Token::LambdaArrow
+    }
+
+    fn parse_return(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+        if self.function_depth == 0 {
+            return Err(ParseError::ReturnOutsideFunction { span: start });
         }
 
-        expr
+        let value = match self.current_token() {
+            Token::Semicolon | Token::RBrace | Token::EOF => None,
+            _ => Some(Box::new(self.parse_expr()?)),
+        };
+        Ok(Expr::new(ExprKind::Return(value), self.span_from(start)))
     }
 
-    fn parse_expr(&mut self) -> Expr {
-        self.parse_term()
+    fn parse_print(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+
+        self.expect(Token::LeftParen, "'(' after 'print'")?;
+        let to_print = self.parse_expr()?;
+        self.expect(Token::RightParen, "')' after expression in print statement")?;
+
+        let span = self.span_from(start);
+        if self.current_token() == Token::Semicolon {
+            self.advance();
+        }
+        Ok(Expr::new(ExprKind::PrintStmt(Box::new(to_print)), span))
     }
 
-    fn parse_term(&mut self) -> Expr {
-        let mut node = self.parse_factor();
+    fn parse_var_declaration(&mut self, is_immut: bool) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+        if let Token::Ident(name) = self.current_token() {
+            self.advance();
+            self.expect(Token::Equal, "'=' after variable name in declaration")?;
+            let value = self.parse_expr()?;
+            Ok(Expr::new(ExprKind::VarDecl(is_immut, name, Box::new(value)), self.span_from(start)))
+        } else {
+            Err(self.unexpected("identifier after 'const' or 'mutate'"))
+        }
+    }
+
+    fn parse_assignment(&mut self) -> Result<Expr, ParseError> {
+        let expr = self.parse_expr()?;
+
+        let operator = self.current_token();
+        let compound = compound_operator(&operator);
+        if compound.is_none() && !matches!(operator, Token::Equal | Token::MaybeAssign) {
+            return Ok(expr);
+        }
+        self.advance();
+        if !is_assignable(&expr) {
+            return Err(ParseError::InvalidAssignmentTarget { span: expr.span });
+        }
+
+        let value = self.parse_expr()?;
+        let span = expr.span.to(value.span);
+        let (target, value) = (Box::new(expr), Box::new(value));
+        let kind = match (operator, compound) {
+            (_, Some(op)) => ExprKind::CompoundAssign(target, op, value),
+            (Token::MaybeAssign, None) => ExprKind::MaybeAssign(target, value),
+            _ => ExprKind::Assign(target, value),
+        };
+        Ok(Expr::new(kind, span))
+    }
+
+    /// Parses `name := value`, shorthand for `mutate name = value`.
+    fn parse_short_declaration(&mut self, name: String) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+        self.advance();
+        let value = self.parse_expr()?;
+        Ok(Expr::new(ExprKind::VarDecl(false, name, Box::new(value)), self.span_from(start)))
+    }
+
+    /// Parses `nullify target`.
+    fn parse_nullify(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+        let target = self.parse_postfix()?;
+        if !is_assignable(&target) {
+            return Err(ParseError::InvalidAssignmentTarget { span: target.span });
+        }
+        Ok(Expr::new(ExprKind::Nullify(Box::new(target)), self.span_from(start)))
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(0)
+    }
 
-        while matches!(self.current_token(), Token::Plus | Token::Minus) {
+    /// Precedence climbing: parses operators that bind at least as tightly
+    /// as `min_precedence`. All of these operators are left-associative.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, ParseError> {
+        let mut node = self.parse_unary()?;
+
+        loop {
+            let start = node.span;
+            let token = self.current_token();
+            let (op, precedence) = match (&token, binary_operator(&token)) {
+                (_, Some((op, precedence))) => (Some(op), precedence),
+                (Token::PipeForward, None) => (None, PIPE_PRECEDENCE),
+                (Token::Backtick, None) => (None, INFIX_CALL_PRECEDENCE),
+                _ => break,
+            };
+            if precedence < min_precedence {
+                break;
+            }
+            self.advance();
+
+            let kind = match op {
+                Some(op) => {
+                    let right = self.parse_binary(precedence + 1)?;
+                    ExprKind::Binary(Box::new(node), op, Box::new(right))
+                }
+                None if token == Token::PipeForward => {
+                    let right = self.parse_binary(precedence + 1)?;
+                    // A call in parentheses is a value like any other: `x |> (h(1))`
+                    // calls whatever `h(1)` returns.
+                    match right.kind {
+                        ExprKind::Call { callee, mut args } if callee.span.start == right.span.start => {
+                            args.insert(0, node);
+                            ExprKind::Call { callee, args }
+                        }
+                        _ => ExprKind::Call { callee: Box::new(right), args: vec![node] },
+                    }
+                }
+                None => {
+                    let callee_span = self.current_span();
+                    let Some(name) = self.current_name() else {
+                        return Err(self.unexpected("function name after '`'"));
+                    };
+                    self.advance();
+                    self.expect(Token::Backtick, "'`' after the function name")?;
+                    let callee = Expr::new(ExprKind::Var(name), callee_span);
+                    let right = self.parse_binary(precedence + 1)?;
+                    ExprKind::Call { callee: Box::new(callee), args: vec![node, right] }
+                }
+            };
+            node = Expr::new(kind, self.span_from(start));
+        }
+
+        Ok(node)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || {
+            let start = self.current_span();
             let op = match self.current_token() {
-                Token::Plus => BinOp::Plus,
-                Token::Minus => BinOp::Minus,
-                _ => unreachable!(),
+                Token::Minus => UnaryOp::Negate,
+                Token::Bang => UnaryOp::Not,
+                Token::Tilde => UnaryOp::BitNot,
+                _ => return self.parse_power(),
+            };
+            self.advance();
+            let operand = self.parse_unary()?;
+            Ok(Expr::new(ExprKind::Unary(op, Box::new(operand)), self.span_from(start)))
+        })
+    }
+
+    /// Parses `base ** exponent`. It is right-associative and binds tighter
+    /// than a unary operator on its left, so `-2 ** 2` is `-(2 ** 2)`, but
+    /// the exponent may itself be negated: `2 ** -1`.
+    fn parse_power(&mut self) -> Result<Expr, ParseError> {
+        let base = self.parse_postfix()?;
+        if self.current_token() != Token::Exponent {
+            return Ok(base);
+        }
+        self.advance();
+        let exponent = self.parse_unary()?;
+        let span = base.span.to(exponent.span);
+        Ok(Expr::new(ExprKind::Binary(Box::new(base), BinOp::Power, Box::new(exponent)), span))
+    }
+
+    /// Parses a primary expression followed by any number of calls, indexes
+    /// and fields, `f(a)[0].name(b)`.
+    fn parse_postfix(&mut self) -> Result<Expr, ParseError> {
+        let mut node = self.parse_primary()?;
+
+        loop {
+            let start = node.span;
+            let kind = match self.current_token() {
+                Token::LeftParen => {
+                    self.advance();
+                    let args = self.parse_comma_separated(Token::RightParen)?;
+                    self.expect(Token::RightParen, "')' after arguments")?;
+                    ExprKind::Call { callee: Box::new(node), args }
+                }
+                Token::LBracket => {
+                    self.advance();
+                    let index = self.parse_expr()?;
+                    self.expect(Token::RBracket, "']' after the index")?;
+                    ExprKind::Index { target: Box::new(node), index: Box::new(index) }
+                }
+                Token::Dot => {
+                    self.advance();
+                    let Some(name) = self.current_name() else {
+                        return Err(self.unexpected("field name after '.'"));
+                    };
+                    self.advance();
+                    ExprKind::Field { target: Box::new(node), name }
+                }
+                _ => break,
             };
+            node = Expr::new(kind, self.span_from(start));
+        }
+
+        Ok(node)
+    }
+
+    /// Parses expressions separated by commas up to (not including) `end`.
+    /// A trailing comma is allowed.
+    fn parse_comma_separated(&mut self, end: Token) -> Result<Vec<Expr>, ParseError> {
+        let mut items = Vec::new();
+        while self.current_token() != end {
+            items.push(self.parse_expr()?);
+            if self.current_token() != Token::Comma {
+                break;
+            }
             self.advance();
-            let right = self.parse_factor();
-            node = Expr::Binary(Box::new(node), op, Box::new(right));
         }
+        Ok(items)
+    }
+
+    /// Parses `whether (cond) { ... }` with any number of `otherwise whether`
+    /// branches and an optional final `otherwise`.
+    fn parse_if(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
 
-        node
+        let condition = self.parse_expr()?;
+        let then_branch = self.parse_braced_block()?;
+
+        let else_branch = if self.current_token() == Token::Otherwise {
+            self.advance();
+            if self.current_token() == Token::Whether {
+                Some(Box::new(self.parse_if()?))
+            } else {
+                Some(Box::new(self.parse_braced_block()?))
+            }
+        } else {
+            None
+        };
+
+        Ok(Expr::new(
+            ExprKind::If { condition: Box::new(condition), then_branch: Box::new(then_branch), else_branch },
+            self.span_from(start),
+        ))
     }
 
-    fn parse_factor(&mut self) -> Expr {
-        let mut node = self.parse_primary();
+    /// Parses `match subject { pattern whether guard => body, ... }`. Each
+    /// body is a braced block or a single statement.
+    fn parse_match(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+        let subject = self.parse_expr()?;
+        let header = start.to(subject.span);
+        self.expect(Token::LBrace, "'{' after the value to match")?;
 
-        while matches!(self.current_token(), Token::Asterisk | Token::Slash | Token::Percent) {
-            let op = match self.current_token() {
-                Token::Asterisk => BinOp::Multiply,
-                Token::Slash => BinOp::Divide,
-                Token::Percent => BinOp::Mod,
-                _ => unreachable!(),
+        let mut arms = Vec::new();
+        while !matches!(self.current_token(), Token::RBrace | Token::EOF) {
+            let pattern = self.parse_pattern()?;
+            let guard = if self.current_token() == Token::Whether {
+                self.advance();
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
+            self.expect(Token::EqualGreater, "'=>' after the pattern")?;
+            let braced = self.current_token() == Token::LBrace;
+            let body = if braced { self.parse_braced_block()? } else { self.parse_statement()? };
+            arms.push(MatchArm { pattern, guard, body });
+
+            // Like a statement, an arm ending in `}` needs no separator.
+            if self.current_token() == Token::Comma {
+                self.advance();
+            } else if !braced {
+                break;
+            }
+        }
+        self.expect(Token::RBrace, "'}' to close the match")?;
+
+        if !arms.iter().any(|arm| arm.guard.is_none() && arm.pattern.is_irrefutable()) {
+            self.warnings.push(ParseWarning::NonExhaustiveMatch { span: header });
+        }
+        Ok(Expr::new(ExprKind::Match { subject: Box::new(subject), arms }, self.span_from(start)))
+    }
+
+    /// Parses a pattern, including `a | b` alternatives.
+    fn parse_pattern(&mut self) -> Result<Pattern, ParseError> {
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || {
+            let first = self.parse_single_pattern()?;
+            if self.current_token() != Token::Bar {
+                return Ok(first);
+            }
+
+            let mut alternatives = vec![first];
+            while self.current_token() == Token::Bar {
+                self.advance();
+                alternatives.push(self.parse_single_pattern()?);
+            }
+            Ok(Pattern::Or(alternatives))
+        })
+    }
+
+    fn parse_single_pattern(&mut self) -> Result<Pattern, ParseError> {
+        match self.current_token() {
+            Token::Ident(name) => {
+                self.advance();
+                if name == "_" || name == "default" {
+                    Ok(Pattern::Wildcard)
+                } else {
+                    Ok(Pattern::Binding(name))
+                }
+            }
+            Token::LBracket => self.parse_list_pattern(),
+            Token::Hash => self.parse_map_pattern(),
+            _ => {
+                let start = self.parse_literal_pattern()?;
+                let inclusive = match self.current_token() {
+                    Token::DotDot => false,
+                    Token::DotDotEqual => true,
+                    _ => return Ok(Pattern::Literal(start)),
+                };
+                self.advance();
+                let end = self.parse_literal_pattern()?;
+                Ok(Pattern::Range { start, end, inclusive })
+            }
+        }
+    }
+
+    /// Parses a literal inside a pattern: a number, which may be negative,
+    /// a string, a boolean or null.
+    fn parse_literal_pattern(&mut self) -> Result<Box<Expr>, ParseError> {
+        let start = self.current_span();
+        let negative = self.current_token() == Token::Minus;
+        if negative {
+            self.advance();
+        }
+
+        let literal_span = self.current_span();
+        let kind = match self.current_token() {
+            Token::Number(value) => ExprKind::Number(value),
+            Token::Integer(value) => ExprKind::Integer(value),
+            Token::String(value) if !negative => ExprKind::String(value),
+            Token::Boolean(value) if !negative => ExprKind::Boolean(value),
+            Token::Null if !negative => ExprKind::Null,
+            _ => return Err(self.unexpected("a pattern")),
+        };
+        self.advance();
+
+        let literal = Expr::new(kind, literal_span);
+        if negative {
+            Ok(Box::new(Expr::new(ExprKind::Unary(UnaryOp::Negate, Box::new(literal)), self.span_from(start))))
+        } else {
+            Ok(Box::new(literal))
+        }
+    }
+
+    /// Parses `[a, b, ..rest, z]`.
+    fn parse_list_pattern(&mut self) -> Result<Pattern, ParseError> {
+        self.advance();
+        let mut before = Vec::new();
+        let mut rest = None;
+        let mut after = Vec::new();
+
+        while self.current_token() != Token::RBracket {
+            if self.current_token() == Token::DotDot && rest.is_none() {
+                self.advance();
+                let name = match self.current_token() {
+                    Token::Ident(name) => {
+                        self.advance();
+                        Some(name)
+                    }
+                    _ => None,
+                };
+                rest = Some(name);
+            } else if rest.is_some() {
+                after.push(self.parse_pattern()?);
+            } else {
+                before.push(self.parse_pattern()?);
+            }
+
+            if self.current_token() != Token::Comma {
+                break;
+            }
+            self.advance();
+        }
+        self.expect(Token::RBracket, "']' to close the list pattern")?;
+
+        Ok(Pattern::List { before, rest, after })
+    }
+
+    /// Parses `#{ key: pattern, other, "quoted key": pattern }`.
+    fn parse_map_pattern(&mut self) -> Result<Pattern, ParseError> {
+        self.advance();
+        self.expect(Token::LBrace, "'{' after '#'")?;
+
+        let mut entries = Vec::new();
+        while self.current_token() != Token::RBrace {
+            let (key, is_name) = match (self.current_token(), self.current_name()) {
+                (Token::Ident(key), _) => (key, true),
+                (Token::String(key), _) | (_, Some(key)) => (key, false),
+                _ => return Err(self.unexpected("a key in the map pattern")),
+            };
+            self.advance();
+
+            let pattern = if self.current_token() == Token::Colon {
+                self.advance();
+                self.parse_pattern()?
+            } else if is_name {
+                Pattern::Binding(key.clone())
+            } else {
+                return Err(self.unexpected("':' after the key"));
             };
+            entries.push((key, pattern));
+
+            if self.current_token() != Token::Comma {
+                break;
+            }
             self.advance();
-            let right = self.parse_primary();
-            node = Expr::Binary(Box::new(node), op, Box::new(right));
         }
+        self.expect(Token::RBrace, "'}' to close the map pattern")?;
 
-        node
+        Ok(Pattern::Map(entries))
     }
 
-    fn parse_primary(&mut self) -> Expr {
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let span = self.current_span();
         match self.current_token() {
+            Token::Whether => self.parse_if(),
+            Token::Match => self.parse_match(),
             Token::Number(value) => {
                 self.advance();
-                Expr::Number(value)
+                Ok(Expr::new(ExprKind::Number(value), span))
+            }
+            Token::Integer(value) => {
+                self.advance();
+                Ok(Expr::new(ExprKind::Integer(value), span))
+            }
+            Token::Boolean(value) => {
+                self.advance();
+                Ok(Expr::new(ExprKind::Boolean(value), span))
+            }
+            Token::Null => {
+                self.advance();
+                Ok(Expr::new(ExprKind::Null, span))
+            }
+            Token::Lambda => self.parse_lambda(),
+            Token::LeftParen if self.at_lambda_params() => self.parse_lambda(),
+            Token::LeftParen => {
+                self.advance();
+                let mut inner = self.parse_expr()?;
+                self.expect(Token::RightParen, "')' to close the parenthesis")?;
+                inner.span = self.span_from(span);
+                Ok(inner)
+            }
+            Token::String(value) => {
+                self.advance();
+                Ok(Expr::new(ExprKind::String(value), span))
+            }
+            Token::Template(parts) => {
+                self.advance();
+                let mut pieces = Vec::new();
+                for part in parts {
+                    match part {
+                        TemplatePart::Text(text) => pieces.push(Expr::new(ExprKind::String(text), span)),
+                        TemplatePart::Code(tokens) => {
+                            let mut parser = Parser::new(tokens);
+                            pieces.push(parser.parse_interpolation()?);
+                            self.warnings.append(&mut parser.warnings);
+                        }
+                    }
+                }
+                Ok(Expr::new(ExprKind::Interpolation(pieces), span))
+            }
+            Token::Ident(name) => {
+                self.advance();
+                Ok(Expr::new(ExprKind::Var(name), span))
+            }
+            // Built-in functions are named by keywords but are ordinary values.
+            Token::Map | Token::Filter | Token::Reduce | Token::Fold | Token::Zip
+            | Token::Partial | Token::Compose | Token::Pipe | Token::Memoize => {
+                let name = self.current_token().to_string();
+                self.advance();
+                Ok(Expr::new(ExprKind::Var(name), span))
             }
-            Token::Ident(ref name) => {
-                let var_name = name.clone();
+            Token::LBracket => {
                 self.advance();
-                Expr::Var(var_name)
+                let items = self.parse_comma_separated(Token::RBracket)?;
+                self.expect(Token::RBracket, "']' to close the list")?;
+                Ok(Expr::new(ExprKind::List(items), self.span_from(span)))
             }
-            Token::EOF => panic!("Unexpected end of input"),
-            _ => panic!("Unexpected token: {:?}", self.current_token()),
+            Token::Hash => self.parse_map(),
+            _ => Err(self.unexpected("an expression")),
         }
     }
+
+    /// Parses `#{ key: value, "quoted key": value }`.
+    fn parse_map(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span();
+        self.advance();
+        self.expect(Token::LBrace, "'{' after '#'")?;
+
+        let mut entries = Vec::new();
+        while self.current_token() != Token::RBrace {
+            let key = match (self.current_token(), self.current_name()) {
+                (Token::String(key), _) | (_, Some(key)) => key,
+                _ => return Err(self.unexpected("a key in the map")),
+            };
+            self.advance();
+            self.expect(Token::Colon, "':' after the key")?;
+            entries.push((key, self.parse_expr()?));
+
+            if self.current_token() != Token::Comma {
+                break;
+            }
+            self.advance();
+        }
+        self.expect(Token::RBrace, "'}' to close the map")?;
+
+        Ok(Expr::new(ExprKind::Map(entries), self.span_from(start)))
+    }
+
+    /// Parses the tokens of a single `${...}` interpolation.
+    fn parse_interpolation(&mut self) -> Result<Expr, ParseError> {
+        let expr = self.parse_expr()?;
+        if self.current_token() != Token::EOF {
+            return Err(self.unexpected("'}' to close the interpolation"));
+        }
+        Ok(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+
+    fn parse(source: &str) -> Result<Expr, Vec<ParseError>> {
+        Parser::new(tokenize(source).expect("source should lex")).parse()
+    }
+
+    fn error_lines(source: &str) -> Vec<usize> {
+        parse(source).unwrap_err().iter().map(|err| err.span().line).collect()
+    }
+
+    #[test]
+    fn recovers_to_report_every_bad_statement() {
+        assert_eq!(error_lines("const = 1;\nprint(2);\nmutate y 3;"), vec![1, 3]);
+    }
+
+    #[test]
+    fn recovers_inside_a_block_and_carries_on_after_it() {
+        assert_eq!(error_lines("whether (true) {\n    const = 1;\n}\nprint(1 +);"), vec![2, 4]);
+    }
+
+    #[test]
+    fn statements_after_an_error_add_no_errors_of_their_own() {
+        assert_eq!(error_lines("print((1 + 2);\nconst ok = 3;\nprint(ok);"), vec![1]);
+    }
+
+    #[test]
+    fn loop_jump_takes_a_label_only_from_its_own_line() {
+        assert!(parse("outer: forever { forever { break outer } }").is_ok());
+        assert!(parse("mutate x = 0; forever {\n    break\n    x = 1\n}").is_ok());
+        assert!(matches!(parse("forever { break nowhere }").unwrap_err()[..], [ParseError::UnknownLabel { .. }]));
+    }
+
+    #[test]
+    fn loop_jump_does_not_take_an_assignment_as_its_label() {
+        assert!(parse("mutate x = 0; forever { break x += 1 }").is_ok());
+        assert!(parse("forever { continue y := 1 }").is_ok());
+    }
+
+    #[test]
+    fn memoize_call_on_the_line_before_a_declaration_stays_a_call() {
+        let statements = |source| match parse(source).unwrap().kind {
+            ExprKind::Block(statements) => statements,
+            other => panic!("expected a block, found {:?}", other),
+        };
+        let split = statements("memoize(f)\nfn g() { 1 }");
+        assert!(matches!(split[0].kind, ExprKind::Call { .. }));
+        assert!(matches!(split[1].kind, ExprKind::FnDecl { .. }));
+        assert!(matches!(statements("memoize(8) fn g() { 1 }")[0].kind, ExprKind::Memoize { .. }));
+    }
+
+    #[test]
+    fn compare_break_can_be_followed_by_default_without_a_semicolon() {
+        let source = "compare 3 { case 3: print(\"three\") break default: print(\"other\") }";
+        assert!(parse(source).is_ok());
+        assert!(parse("compare 3 {\n    case 3:\n        print(\"three\")\n        break\n    default:\n        print(\"other\")\n}").is_ok());
+    }
+
+    #[test]
+    fn deeply_nested_code_parses_on_a_small_stack() {
+        let depth = 5000;
+        let parens = format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&parens).is_ok());
+        let negations = format!("{}1", "-".repeat(depth));
+        assert!(parse(&negations).is_ok());
+        let blocks = format!("{}1{}", "whether (true) { ".repeat(depth), " }".repeat(depth));
+        assert!(parse(&blocks).is_ok());
+        let patterns = format!("match 1 {{ {}x{} => 1, _ => 2 }}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&patterns).is_ok());
+    }
+
+    #[test]
+    fn match_arms_with_block_bodies_need_no_comma() {
+        let source = "match 1 {\n    0 => { print(0) }\n    1 => { print(1) },\n    default => print(2)\n}";
+        let Ok(program) = parse(source) else { panic!("{} should parse", source) };
+        let ExprKind::Block(statements) = program.kind else { panic!("expected a block") };
+        assert!(matches!(&statements[0].kind, ExprKind::Match { arms, .. } if arms.len() == 3));
+        assert!(parse("match 1 { 0 => print(0) 1 => print(1) }").is_err());
+    }
+
+    #[test]
+    fn match_without_a_catch_all_arm_is_warned_about() {
+        let warnings = |source| {
+            let mut parser = Parser::new(tokenize(source).unwrap());
+            parser.parse().unwrap();
+            parser.warnings().to_vec()
+        };
+        assert!(matches!(warnings("match 1 { 1 => 2 }")[..], [ParseWarning::NonExhaustiveMatch { .. }]));
+        assert!(matches!(warnings("match 1 { n whether n > 0 => 2 }")[..], [ParseWarning::NonExhaustiveMatch { .. }]));
+        assert!(warnings("match 1 { 1 => 2, n => n }").is_empty());
+        assert!(warnings("match 1 { _ | 1 => 2 }").is_empty());
+    }
+
+    #[test]
+    fn pipe_splices_into_a_bare_call_only() {
+        let piped = |source| match parse(source).unwrap().kind {
+            ExprKind::Block(mut statements) => match statements.remove(0).kind {
+                ExprKind::Call { callee, args } => (callee.kind, args.len()),
+                other => panic!("expected a call, found {:?}", other),
+            },
+            other => panic!("expected a block, found {:?}", other),
+        };
+        assert_eq!(piped("x |> f"), (ExprKind::Var("f".to_string()), 1));
+        assert_eq!(piped("x |> g(2)"), (ExprKind::Var("g".to_string()), 2));
+        assert!(matches!(piped("x |> (h(1))"), (ExprKind::Call { .. }, 1)));
+        assert!(matches!(piped("x |> (k)(1)"), (ExprKind::Var(_), 2)));
+    }
 }
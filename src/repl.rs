@@ -0,0 +1,267 @@
+use std::env;
+use std::path::PathBuf;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use berry_lang::diagnostic::{ Diagnostic, RenderMode, Renderer };
+use berry_lang::interpreter::{ Interpreter, Value };
+use berry_lang::lexer::{ tokenize, SpannedToken };
+use berry_lang::parser::{ Expr, ExprKind, Parser };
+
+const PROMPT: &str = "berry> ";
+const CONTINUATION_PROMPT: &str = "  ...> ";
+const HISTORY_FILE: &str = ".berry_history";
+const SOURCE_NAME: &str = "<repl>";
+
+const HELP: &str = "\
+:vars [name]    List bindings, or show a single one
+:reset          Forget every binding
+:ast <code>     Show the syntax tree for <code>
+:tokens <code>  Show the tokens for <code>
+:help           Show this help
+:quit           Leave the REPL (Ctrl-D works too)";
+
+pub fn run(args: Vec<String>, mode: RenderMode) -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means this is the first session.
+        let _ = editor.load_history(path);
+    }
+
+    println!("Berry REPL. Type :help for commands.");
+
+    let mut interpreter = Interpreter::with_args(args.clone());
+    let mut buffer = String::new();
+
+    loop {
+        let prompt = if buffer.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err),
+        };
+
+        if buffer.is_empty() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if let Some(command) = trimmed.strip_prefix(':') {
+                editor.add_history_entry(trimmed)?;
+                match run_command(command, &mut interpreter, &args, mode) {
+                    Flow::Continue => continue,
+                    Flow::Quit => break,
+                }
+            }
+        }
+
+        buffer.push_str(&line);
+        buffer.push('\n');
+        if is_incomplete(&buffer) {
+            continue;
+        }
+
+        let input = std::mem::take(&mut buffer);
+        editor.add_history_entry(input.trim_end())?;
+        eval(&input, &mut interpreter, mode);
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+enum Flow {
+    Continue,
+    Quit,
+}
+
+fn run_command(command: &str, interpreter: &mut Interpreter, args: &[String], mode: RenderMode) -> Flow {
+    let (name, rest) = match command.split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, rest.trim()),
+        None => (command, ""),
+    };
+
+    match name {
+        "vars" if rest.is_empty() => {
+            for (name, is_immut, value) in interpreter.variables() {
+                let kind = if is_immut { "const" } else { "mutate" };
+                println!("{} {} = {}", kind, name, value.repr());
+            }
+        }
+        "vars" => match interpreter.get_variable(rest) {
+            Some(value) => println!("{} = {}", rest, value.repr()),
+            None => println!("error: Undefined variable: {}", rest),
+        },
+        "reset" => {
+            *interpreter = Interpreter::with_args(args.to_vec());
+            println!("All bindings cleared.");
+        }
+        "ast" => {
+            if let Some(ast) = parse(rest, mode) {
+                println!("{:#?}", ast);
+            }
+        }
+        "tokens" => match lex(rest) {
+            Ok(tokens) => {
+                for SpannedToken { token, span, leading_trivia } in tokens {
+                    for trivia in leading_trivia {
+                        println!("{}:{}\t{:?} {:?}", trivia.span.line, trivia.span.column, trivia.kind, trivia.text);
+                    }
+                    println!("{}:{}\t{:?}", span.line, span.column, token);
+                }
+            }
+            Err(errors) => report(rest, &errors, mode),
+        },
+        "help" => println!("{}", HELP),
+        "quit" | "q" => return Flow::Quit,
+        _ => println!("error: unknown command :{} (try :help)", name),
+    }
+
+    Flow::Continue
+}
+
+fn eval(input: &str, interpreter: &mut Interpreter, mode: RenderMode) {
+    let Some(ast) = parse(input, mode) else {
+        return;
+    };
+
+    match interpreter.interpret(&ast) {
+        // Like an empty line, a null result has nothing to say.
+        Ok(Value::Null) => {}
+        Ok(value) if should_echo(&ast) => println!("{}", value.repr()),
+        Ok(_) => {}
+        Err(err) => report(input, &[Diagnostic::from(&err)], mode),
+    }
+}
+
+/// Declarations, `print` and loops already speak for themselves; everything
+/// else gets its value echoed back.
+fn should_echo(ast: &Expr) -> bool {
+    match &ast.kind {
+        ExprKind::Block(statements) => match statements.last() {
+            Some(last) => should_echo(last),
+            None => false,
+        },
+        ExprKind::VarDecl(..)
+        | ExprKind::FnDecl { .. }
+        | ExprKind::Memoize { .. }
+        | ExprKind::PrintStmt(_)
+        | ExprKind::Forever { .. }
+        | ExprKind::Foreach { .. }
+        | ExprKind::Compare { .. } => false,
+        _ => true,
+    }
+}
+
+fn lex(input: &str) -> Result<Vec<SpannedToken>, Vec<Diagnostic>> {
+    tokenize(input).map_err(|errors| errors.iter().map(Diagnostic::from).collect())
+}
+
+/// Parses `input`, reporting any errors and warnings along the way.
+fn parse(input: &str, mode: RenderMode) -> Option<Expr> {
+    let tokens = match lex(input) {
+        Ok(tokens) => tokens,
+        Err(errors) => {
+            report(input, &errors, mode);
+            return None;
+        }
+    };
+
+    let mut parser = Parser::new(tokens);
+    let parsed = parser.parse();
+    let warnings: Vec<Diagnostic> = parser.warnings().iter().map(Diagnostic::from).collect();
+    report(input, &warnings, mode);
+    match parsed {
+        Ok(ast) => Some(ast),
+        Err(errors) => {
+            report(input, &errors.iter().map(Diagnostic::from).collect::<Vec<_>>(), mode);
+            None
+        }
+    }
+}
+
+fn report(input: &str, diagnostics: &[Diagnostic], mode: RenderMode) {
+    let renderer = Renderer::new(SOURCE_NAME, input, mode);
+    for diagnostic in diagnostics {
+        eprint!("{}", renderer.render(diagnostic));
+    }
+}
+
+/// Input is incomplete while it has more openers than closers outside of
+/// string literals and comments, so a `{` at the end of a line asks for more
+/// lines. An unclosed string or block comment also asks for more.
+fn is_incomplete(input: &str) -> bool {
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut comment_depth = 0;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if comment_depth > 0 {
+            if c == '*' && chars.next_if_eq(&'/').is_some() {
+                comment_depth -= 1;
+            } else if c == '/' && chars.next_if_eq(&'*').is_some() {
+                comment_depth += 1;
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = !in_string,
+            '\\' if in_string => {
+                chars.next();
+            }
+            _ if in_string => {}
+            '/' if chars.next_if_eq(&'/').is_some() => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            }
+            '/' if chars.next_if_eq(&'*').is_some() => comment_depth += 1,
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    depth > 0 || in_string || comment_depth > 0
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_brace_waits_for_more() {
+        assert!(is_incomplete("fn f() {"));
+        assert!(!is_incomplete("fn f() { 1 }"));
+    }
+
+    #[test]
+    fn brackets_inside_strings_do_not_count() {
+        assert!(!is_incomplete("print(\"{\");"));
+        assert!(!is_incomplete("print(\"\\\"{\");"));
+    }
+
+    #[test]
+    fn template_with_a_quoted_string_inside_is_complete() {
+        assert!(!is_incomplete("print(\"${\"x\"}\");"));
+    }
+
+    #[test]
+    fn unclosed_block_comment_waits_for_more() {
+        assert!(is_incomplete("1 /* not done"));
+        assert!(is_incomplete("1 /* /* */"));
+        assert!(!is_incomplete("1 /* /* */ */"));
+    }
+}